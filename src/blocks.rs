@@ -0,0 +1,320 @@
+use crate::formatting::slack_to_irc;
+use serde_json::Value;
+
+const BOLD: &str = "\x02";
+
+// Renders a Slack message for IRC. Messages with blocks usually only have a fallback as text,
+// so we show the blocks instead if we can, then the attachments after.
+pub async fn render_message_text(text: &str, blocks: &[Value], attachments: &[Value]) -> String {
+    let mut lines = flatten_blocks(blocks).await;
+    if lines.is_empty() && !text.is_empty() {
+        lines.push(slack_to_irc(text).await);
+    }
+    for attachment in attachments {
+        lines.append(&mut flatten_attachment(attachment).await);
+    }
+    lines.join("\n")
+}
+
+// Bots and apps don't post as a Slack user, this is the nick they get on IRC instead
+pub fn bot_nick(username: Option<&str>, bot_name: Option<&str>, bot_id: Option<&str>) -> String {
+    let name = username
+        .or(bot_name)
+        .or(bot_id)
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or("bot");
+    name.trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_[]{}\\|^`".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+// Sections, headers, context and the like. Rich text is skipped, the message text has it.
+async fn flatten_blocks(blocks: &[Value]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for block in blocks {
+        match block["type"].as_str() {
+            Some("header") => {
+                if let Some(text) = render_text_object(&block["text"]).await {
+                    lines.push(BOLD.to_owned() + &text + BOLD);
+                }
+            }
+            Some("section") => {
+                if let Some(text) = render_text_object(&block["text"]).await {
+                    lines.push(text);
+                }
+                let mut fields = Vec::new();
+                for field in block["fields"].as_array().into_iter().flatten() {
+                    if let Some(field) = render_text_object(field).await {
+                        fields.push(field.replace('\n', " "));
+                    }
+                }
+                if !fields.is_empty() {
+                    lines.push(fields.join(" | "));
+                }
+                if let Some(link) = render_element(&block["accessory"]).await {
+                    lines.push(link);
+                }
+            }
+            Some("context") => {
+                let mut elements = Vec::new();
+                for element in block["elements"].as_array().into_iter().flatten() {
+                    if let Some(element) = render_element(element).await {
+                        elements.push(element);
+                    }
+                }
+                if !elements.is_empty() {
+                    lines.push(elements.join(" | "));
+                }
+            }
+            Some("actions") => {
+                let mut elements = Vec::new();
+                for element in block["elements"].as_array().into_iter().flatten() {
+                    if let Some(element) = render_element(element).await {
+                        elements.push(element);
+                    }
+                }
+                if !elements.is_empty() {
+                    lines.push(elements.join(" | "));
+                }
+            }
+            Some("image") => {
+                let title = render_text_object(&block["title"]).await;
+                let alt_text = block["alt_text"].as_str().map(|alt| alt.to_owned());
+                if let Some(url) = block["image_url"].as_str() {
+                    let label = title.or(alt_text).unwrap_or_else(|| "image".to_owned());
+                    lines.push(format!("{} ({})", label, url));
+                }
+            }
+            _ => (),
+        }
+    }
+    lines
+}
+
+// Text objects are either mrkdwn, or plain text shown as is
+async fn render_text_object(text_object: &Value) -> Option<String> {
+    let text = text_object["text"].as_str()?;
+    if text.trim().is_empty() {
+        return None;
+    }
+    match text_object["type"].as_str() {
+        Some("mrkdwn") => Some(slack_to_irc(text).await),
+        _ => Some(text.to_owned()),
+    }
+}
+
+// Buttons only make sense on IRC if they're links, images are shown by their alt text
+async fn render_element(element: &Value) -> Option<String> {
+    match element["type"].as_str()? {
+        "mrkdwn" | "plain_text" => render_text_object(element).await,
+        "image" => element["alt_text"]
+            .as_str()
+            .filter(|alt| !alt.is_empty())
+            .map(|alt| alt.to_owned()),
+        "button" => {
+            let url = element["url"].as_str()?;
+            let label = render_text_object(&element["text"])
+                .await
+                .unwrap_or_else(|| "link".to_owned());
+            Some(format!("{} ({})", label, url))
+        }
+        _ => None,
+    }
+}
+
+// Legacy attachments, which integrations still use a lot
+async fn flatten_attachment(attachment: &Value) -> Vec<String> {
+    let mut lines = Vec::new();
+    // Link unfurls are previews of URLs already in the message text
+    if attachment.get("from_url").is_some() || attachment.get("original_url").is_some() {
+        return lines;
+    }
+    if let Some(pretext) = attachment["pretext"].as_str().filter(|t| !t.is_empty()) {
+        lines.push(slack_to_irc(pretext).await);
+    }
+    if let Some(author) = attachment["author_name"].as_str().filter(|t| !t.is_empty()) {
+        lines.push(author.to_owned());
+    }
+    if let Some(title) = attachment["title"].as_str().filter(|t| !t.is_empty()) {
+        let title = BOLD.to_owned() + &slack_to_irc(title).await + BOLD;
+        match attachment["title_link"].as_str() {
+            Some(link) => lines.push(format!("{} ({})", title, link)),
+            None => lines.push(title),
+        }
+    }
+    if let Some(text) = attachment["text"].as_str().filter(|t| !t.is_empty()) {
+        lines.push(slack_to_irc(text).await);
+    }
+
+    let mut fields = Vec::new();
+    for field in attachment["fields"].as_array().into_iter().flatten() {
+        let title = field["title"].as_str().unwrap_or_default();
+        let value = slack_to_irc(field["value"].as_str().unwrap_or_default()).await;
+        let field = match (title.is_empty(), value.is_empty()) {
+            (true, true) => continue,
+            (true, false) => value,
+            (false, true) => title.to_owned(),
+            (false, false) => format!("{}: {}", title, value),
+        };
+        fields.push(field.replace('\n', " "));
+    }
+    if !fields.is_empty() {
+        lines.push(fields.join(" | "));
+    }
+
+    if let Some(blocks) = attachment["blocks"].as_array() {
+        lines.append(&mut flatten_blocks(blocks).await);
+    }
+    if let Some(footer) = attachment["footer"].as_str().filter(|t| !t.is_empty()) {
+        lines.push(slack_to_irc(footer).await);
+    }
+
+    // The fallback is only meant for clients that can't show any of the above
+    if lines.is_empty() {
+        if let Some(fallback) = attachment["fallback"].as_str().filter(|t| !t.is_empty()) {
+            lines.push(slack_to_irc(fallback).await);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn blocks() {
+        let cases = [
+            (json!([{ "type": "rich_text", "elements": [] }]), vec![]),
+            (
+                json!([{ "type": "header", "text": { "type": "plain_text", "text": "*Title*" } }]),
+                vec!["\x02*Title*\x02"],
+            ),
+            (
+                json!([{
+                    "type": "section",
+                    "text": { "type": "mrkdwn", "text": "*Deploy* done" },
+                    "fields": [
+                        { "type": "mrkdwn", "text": "env\nprod" },
+                        { "type": "plain_text", "text": " " },
+                        { "type": "plain_text", "text": "v2" },
+                    ],
+                    "accessory": {
+                        "type": "button",
+                        "text": { "type": "plain_text", "text": "Logs" },
+                        "url": "https://example.com/logs",
+                    },
+                }]),
+                vec![
+                    "\x02Deploy\x02 done",
+                    "env prod | v2",
+                    "Logs (https://example.com/logs)",
+                ],
+            ),
+            (
+                json!([{
+                    "type": "context",
+                    "elements": [
+                        { "type": "image", "alt_text": "avatar" },
+                        { "type": "mrkdwn", "text": "_by_ someone" },
+                    ],
+                }]),
+                vec!["avatar | \x1dby\x1d someone"],
+            ),
+            (
+                json!([{
+                    "type": "actions",
+                    "elements": [
+                        { "type": "button", "text": { "type": "plain_text", "text": "Approve" } },
+                        { "type": "button", "url": "https://example.com" },
+                    ],
+                }]),
+                vec!["link (https://example.com)"],
+            ),
+            (
+                json!([
+                    { "type": "image", "image_url": "https://example.com/a.png", "alt_text": "A" },
+                    { "type": "image", "image_url": "https://example.com/b.png" },
+                ]),
+                vec![
+                    "A (https://example.com/a.png)",
+                    "image (https://example.com/b.png)",
+                ],
+            ),
+        ];
+        for (blocks, lines) in &cases {
+            let blocks = blocks.as_array().unwrap();
+            assert_eq!(flatten_blocks(blocks).await, *lines, "{}", blocks[0]);
+        }
+    }
+
+    #[tokio::test]
+    async fn attachments() {
+        let cases = [
+            (
+                json!({
+                    "pretext": "New _alert_",
+                    "author_name": "monitor",
+                    "title": "CPU high",
+                    "title_link": "https://example.com/alert",
+                    "text": "at *95%*",
+                    "fields": [
+                        { "title": "Host", "value": "web1" },
+                        { "title": "", "value": "line\nbreak" },
+                        { "title": "", "value": "" },
+                    ],
+                    "footer": "sent by bot",
+                    "fallback": "CPU high on web1",
+                }),
+                vec![
+                    "New \x1dalert\x1d",
+                    "monitor",
+                    "\x02CPU high\x02 (https://example.com/alert)",
+                    "at \x0295%\x02",
+                    "Host: web1 | line break",
+                    "sent by bot",
+                ],
+            ),
+            (
+                json!({ "fallback": "Only a fallback" }),
+                vec!["Only a fallback"],
+            ),
+            (
+                json!({
+                    "blocks": [{ "type": "section", "text": { "type": "mrkdwn", "text": "hi" } }],
+                    "fallback": "unused",
+                }),
+                vec!["hi"],
+            ),
+            (
+                json!({
+                    "from_url": "https://example.com/page",
+                    "title": "Page",
+                    "text": "Preview",
+                    "fallback": "Page",
+                }),
+                vec![],
+            ),
+            (
+                json!({ "original_url": "https://example.com/page", "fallback": "Page" }),
+                vec![],
+            ),
+        ];
+        for (attachment, lines) in &cases {
+            assert_eq!(
+                flatten_attachment(attachment).await,
+                *lines,
+                "{}",
+                attachment
+            );
+        }
+    }
+}
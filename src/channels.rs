@@ -1,56 +1,373 @@
-use std::collections::HashMap;
+use crate::slack::{Channel as SlackChannel, UserInfo};
+use crate::users::{get_away_message, irc_source, make_virtual_user};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
 // Max number of IRC messages waiting to get ACK'd before we start removing the older oness
 const MAX_MSGS_FROM_IRC_BUFFER: usize = 64;
 
+// An IRC channel bridged to a Slack channel, and the IRC clients it was joined for
+struct ChannelRoute {
+    irc_channel: Arc<RwLock<rirc_server::Channel>>,
+    clients: HashSet<SocketAddr>,
+}
+
+// A Slack IM, with the IRC clients on either side of it. Both users of an IM can be using the
+// gateway, so each client gets the ID of the user on the other side from it.
+struct ImRoute {
+    clients: HashMap<SocketAddr, String>,
+}
+
 lazy_static! {
-    static ref GLOBAL_CHANNELS: RwLock<HashMap<String, Arc<RwLock<rirc_server::Channel>>>> =
+    // Team IDs to Slack channel IDs to routes
+    static ref GLOBAL_CHANNEL_ROUTES: RwLock<HashMap<String, HashMap<String, ChannelRoute>>> =
         RwLock::new(HashMap::new());
     static ref GLOBAL_CHANNELS_MSGS_FROM_IRC: RwLock<HashMap<String, RwLock<Vec<String>>>> =
         RwLock::new(HashMap::new());
-    static ref GLOBAL_CHANNELS_ID: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+    // Slack IM channel IDs to routes
+    static ref GLOBAL_IM_ROUTES: RwLock<HashMap<String, ImRoute>> = RwLock::new(HashMap::new());
+    // IRC channel names to the team ID and Slack channel ID they're bridged with
+    static ref GLOBAL_CHANNELS_ID: RwLock<HashMap<String, (String, String)>> =
+        RwLock::new(HashMap::new());
 }
 
 pub async fn register_channel(
+    team_id: String,
     slack_channel_id: String,
+    client_addr: SocketAddr,
     channel: Arc<RwLock<rirc_server::Channel>>,
-) {
+) -> Result<(), String> {
     {
         let channel_guard = channel.read().await;
         let mut channel_ids_guard = GLOBAL_CHANNELS_ID.write().await;
-        channel_ids_guard.insert(channel_guard.name.clone(), slack_channel_id.clone());
+        let bridged_ids = (team_id.clone(), slack_channel_id.clone());
+        match channel_ids_guard.get(&channel_guard.name) {
+            // IRC channels are shared by every client, so don't leak messages across workspaces
+            Some(ids) if *ids != bridged_ids => {
+                return Err(format!(
+                    "IRC channel {} is already bridged to Slack channel {} of team {}",
+                    channel_guard.name, ids.1, ids.0
+                ));
+            }
+            Some(_) => (),
+            None => {
+                channel_ids_guard.insert(channel_guard.name.clone(), bridged_ids);
+            }
+        }
     }
 
+    init_messages_from_irc(&slack_channel_id).await;
+
     {
-        let mut channel_msgs_guard = GLOBAL_CHANNELS_MSGS_FROM_IRC.write().await;
-        channel_msgs_guard.insert(slack_channel_id.clone(), RwLock::new(Vec::new()));
+        let mut routes_guard = GLOBAL_CHANNEL_ROUTES.write().await;
+        let route = routes_guard
+            .entry(team_id)
+            .or_insert_with(HashMap::new)
+            .entry(slack_channel_id)
+            .or_insert_with(|| ChannelRoute {
+                irc_channel: channel,
+                clients: HashSet::new(),
+            });
+        route.clients.insert(client_addr);
+    }
+
+    Ok(())
+}
+
+// Picks the IRC name of a bridged Slack channel and reserves it before anyone joins it.
+// IRC channels are shared by every client, so when another team already bridged a channel of
+// the same name, this one gets the team ID as a suffix instead of leaking messages across.
+pub async fn reserve_irc_channel_name(
+    team_id: &str,
+    slack_channel_id: &str,
+    irc_chan_name: &str,
+) -> String {
+    let mut channel_ids_guard = GLOBAL_CHANNELS_ID.write().await;
+    let bridged_ids = (team_id.to_owned(), slack_channel_id.to_owned());
+    let team_chan_name = format!("{}-{}", irc_chan_name, team_id.to_lowercase());
+    let candidates = std::iter::once(irc_chan_name.to_owned())
+        .chain(std::iter::once(team_chan_name.clone()))
+        .chain((2..).map(|n| format!("{}-{}", team_chan_name, n)));
+    for candidate in candidates {
+        match channel_ids_guard.get(&candidate) {
+            Some(ids) if *ids == bridged_ids => return candidate,
+            Some(_) => continue,
+            None => {
+                channel_ids_guard.insert(candidate.clone(), bridged_ids);
+                return candidate;
+            }
+        }
+    }
+    unreachable!()
+}
+
+// Gives back a reserved IRC name if we couldn't bridge the channel after all
+pub async fn release_irc_channel_name(team_id: &str, slack_channel_id: &str, irc_chan_name: &str) {
+    if get_irc_channel(team_id, slack_channel_id).await.is_some() {
+        return;
     }
+    let mut channel_ids_guard = GLOBAL_CHANNELS_ID.write().await;
+    channel_ids_guard.remove(irc_chan_name);
+}
+
+pub async fn register_im(slack_channel_id: String, user_id: String, client_addr: SocketAddr) {
+    init_messages_from_irc(&slack_channel_id).await;
+
+    let mut im_routes_guard = GLOBAL_IM_ROUTES.write().await;
+    let route = im_routes_guard
+        .entry(slack_channel_id)
+        .or_insert_with(|| ImRoute {
+            clients: HashMap::new(),
+        });
+    route.clients.insert(client_addr, user_id);
+}
+
+async fn init_messages_from_irc(slack_channel_id: &str) {
+    let mut channel_msgs_guard = GLOBAL_CHANNELS_MSGS_FROM_IRC.write().await;
+    channel_msgs_guard
+        .entry(slack_channel_id.to_owned())
+        .or_insert_with(|| RwLock::new(Vec::new()));
+}
+
+pub async fn unregister_channel(team_id: &str, slack_channel_id: &str, client_addr: &SocketAddr) {
+    let dropped_channel = {
+        let mut routes_guard = GLOBAL_CHANNEL_ROUTES.write().await;
+        let team_routes = match routes_guard.get_mut(team_id) {
+            Some(team_routes) => team_routes,
+            None => return,
+        };
+        let route = match team_routes.get_mut(slack_channel_id) {
+            Some(route) => route,
+            None => return,
+        };
+        route.clients.remove(client_addr);
+        if !route.clients.is_empty() {
+            return;
+        }
+        team_routes.remove(slack_channel_id).unwrap().irc_channel
+    };
+
+    let channel_guard = dropped_channel.read().await;
+    let mut channel_ids_guard = GLOBAL_CHANNELS_ID.write().await;
+    channel_ids_guard.remove(&channel_guard.name);
+}
+
+// Forgets the client's routes, and the channels that no other client is bridging anymore
+pub async fn unregister_client(client_addr: &SocketAddr) {
+    let mut dropped_channels = Vec::new();
 
     {
-        let mut channels_guard = GLOBAL_CHANNELS.write().await;
-        channels_guard.insert(slack_channel_id, channel);
+        let mut im_routes_guard = GLOBAL_IM_ROUTES.write().await;
+        im_routes_guard.retain(|_, route| {
+            route.clients.remove(client_addr);
+            !route.clients.is_empty()
+        });
+    }
+
+    {
+        let mut routes_guard = GLOBAL_CHANNEL_ROUTES.write().await;
+        for team_routes in routes_guard.values_mut() {
+            team_routes.retain(|_, route| {
+                route.clients.remove(client_addr);
+                if route.clients.is_empty() {
+                    dropped_channels.push(route.irc_channel.clone());
+                }
+                !route.clients.is_empty()
+            });
+        }
+        routes_guard.retain(|_, team_routes| !team_routes.is_empty());
+    }
+
+    for channel in dropped_channels {
+        let channel_guard = channel.read().await;
+        let mut channel_ids_guard = GLOBAL_CHANNELS_ID.write().await;
+        channel_ids_guard.remove(&channel_guard.name);
+    }
+}
+
+// IRC name of a bridged Slack channel, IMs don't have one since they're bridged as queries
+pub fn irc_channel_name(channel: &SlackChannel) -> Option<String> {
+    let name = channel.name.as_ref()?;
+    if channel.is_mpim {
+        // Slack names mpims like "mpdm-alice--bob--carol-1"
+        let name = match name.rfind('-') {
+            Some(pos) if name[pos + 1..].chars().all(|c| c.is_ascii_digit()) => &name[..pos],
+            _ => name,
+        };
+        Some("&".to_owned() + &name.replace("--", "-"))
+    } else {
+        Some("#".to_owned() + name)
     }
 }
 
-pub async fn get_channel_id(irc_channel_name: &str) -> Option<String> {
+// Adds a Slack user to the IRC channel as a virtual user, and tells the channel they joined
+pub async fn join_virtual_user(irc_chan: &mut rirc_server::Channel, user_info: &UserInfo) {
+    if irc_chan.add_virtual_user(make_virtual_user(user_info)) {
+        let msg = rirc_server::Message {
+            tags: Vec::new(),
+            source: Some(irc_source(&user_info.name)),
+            command: "JOIN".to_owned(),
+            params: vec![irc_chan.name.clone()],
+        };
+        let _ = irc_chan.send(msg, None).await;
+
+        let away_message = get_away_message(&user_info.id);
+        if away_message.is_some() {
+            set_virtual_user_away(irc_chan, &user_info.name, away_message).await;
+        }
+    }
+}
+
+pub async fn part_virtual_user(irc_chan: &mut rirc_server::Channel, username: &str) {
+    if irc_chan.remove_virtual_user(username) {
+        let msg = rirc_server::Message {
+            tags: Vec::new(),
+            source: Some(irc_source(username)),
+            command: "PART".to_owned(),
+            params: vec![irc_chan.name.clone()],
+        };
+        let _ = irc_chan.send(msg, None).await;
+    }
+}
+
+// Marks the virtual user as away (or back), and tells the clients that asked for away-notify
+pub async fn set_virtual_user_away(
+    irc_chan: &mut rirc_server::Channel,
+    username: &str,
+    away_message: Option<String>,
+) {
+    if irc_chan.set_virtual_user_away(username, away_message.clone()) {
+        let msg = rirc_server::Message {
+            tags: Vec::new(),
+            source: Some(irc_source(username)),
+            command: "AWAY".to_owned(),
+            params: away_message.into_iter().collect(),
+        };
+        let _ = irc_chan.send_with_cap(msg, "away-notify", None).await;
+    }
+}
+
+// Sets the IRC channel's topic, and tells the channel who changed it
+pub async fn set_channel_topic(irc_chan: &mut rirc_server::Channel, setter: &str, topic: String) {
+    if irc_chan.set_topic(topic.clone()) {
+        let msg = rirc_server::Message {
+            tags: Vec::new(),
+            source: Some(irc_source(setter)),
+            command: "TOPIC".to_owned(),
+            params: vec![irc_chan.name.clone(), topic],
+        };
+        let _ = irc_chan.send(msg, None).await;
+    }
+}
+
+pub fn is_im_channel_id(slack_channel_id: &str) -> bool {
+    slack_channel_id.starts_with('D')
+}
+
+pub async fn get_channel_id(team_id: &str, irc_channel_name: &str) -> Option<String> {
     let channel_ids_guard = GLOBAL_CHANNELS_ID.read().await;
-    channel_ids_guard.get(irc_channel_name).cloned()
+    match channel_ids_guard.get(irc_channel_name) {
+        Some((channel_team_id, channel_id)) if channel_team_id == team_id => {
+            Some(channel_id.clone())
+        }
+        _ => None,
+    }
 }
 
-pub async fn get_irc_channel(slack_channel_id: &str) -> Option<Arc<RwLock<rirc_server::Channel>>> {
-    let channels_guard = GLOBAL_CHANNELS.read().await;
-    channels_guard.get(slack_channel_id).cloned()
+pub async fn get_irc_channel_name(slack_channel_id: &str) -> Option<String> {
+    let channel_ids_guard = GLOBAL_CHANNELS_ID.read().await;
+    channel_ids_guard
+        .iter()
+        .find(|(_, (_, channel_id))| channel_id == slack_channel_id)
+        .map(|(irc_channel_name, _)| irc_channel_name.clone())
+}
+
+pub async fn get_irc_channel(
+    team_id: &str,
+    slack_channel_id: &str,
+) -> Option<Arc<RwLock<rirc_server::Channel>>> {
+    let routes_guard = GLOBAL_CHANNEL_ROUTES.read().await;
+    routes_guard
+        .get(team_id)
+        .and_then(|team_routes| team_routes.get(slack_channel_id))
+        .map(|route| route.irc_channel.clone())
+}
+
+pub async fn is_bridged_for_client(
+    team_id: &str,
+    slack_channel_id: &str,
+    client_addr: &SocketAddr,
+) -> bool {
+    let routes_guard = GLOBAL_CHANNEL_ROUTES.read().await;
+    match routes_guard
+        .get(team_id)
+        .and_then(|team_routes| team_routes.get(slack_channel_id))
+    {
+        Some(route) => route.clients.contains(client_addr),
+        None => false,
+    }
+}
+
+pub async fn get_irc_channels(team_id: &str) -> Vec<Arc<RwLock<rirc_server::Channel>>> {
+    let routes_guard = GLOBAL_CHANNEL_ROUTES.read().await;
+    routes_guard
+        .get(team_id)
+        .map(|team_routes| {
+            team_routes
+                .values()
+                .map(|route| route.irc_channel.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Returns the bridged channels of the team, each with its Slack channel ID
+pub async fn get_bridged_channels(
+    team_id: &str,
+) -> Vec<(String, Arc<RwLock<rirc_server::Channel>>)> {
+    let routes_guard = GLOBAL_CHANNEL_ROUTES.read().await;
+    routes_guard
+        .get(team_id)
+        .map(|team_routes| {
+            team_routes
+                .iter()
+                .map(|(slack_channel_id, route)| {
+                    (slack_channel_id.clone(), route.irc_channel.clone())
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Returns the IRC clients in the IM, each with the Slack user on the other side from them
+pub async fn get_im_route(slack_channel_id: &str) -> Option<Vec<(SocketAddr, String)>> {
+    let im_routes_guard = GLOBAL_IM_ROUTES.read().await;
+    im_routes_guard.get(slack_channel_id).map(|route| {
+        route
+            .clients
+            .iter()
+            .map(|(addr, user_id)| (*addr, user_id.clone()))
+            .collect()
+    })
+}
+
+pub async fn get_im_channel_id(client_addr: &SocketAddr, user_id: &str) -> Option<String> {
+    let im_routes_guard = GLOBAL_IM_ROUTES.read().await;
+    im_routes_guard
+        .iter()
+        .find(|(_, route)| route.clients.get(client_addr).map(|id| id.as_str()) == Some(user_id))
+        .map(|(channel_id, _)| channel_id.clone())
 }
 
 pub async fn mark_message_from_irc(slack_channel_id: &str, msg_ts: String) {
+    init_messages_from_irc(slack_channel_id).await;
     let channel_msgs_guard = GLOBAL_CHANNELS_MSGS_FROM_IRC.read().await;
-    let mut msgs_guard = channel_msgs_guard
-        .get(slack_channel_id)
-        .unwrap()
-        .write()
-        .await;
+    let mut msgs_guard = match channel_msgs_guard.get(slack_channel_id) {
+        Some(msgs) => msgs.write().await,
+        None => return,
+    };
     msgs_guard.push(msg_ts);
 
     if msgs_guard.len() > MAX_MSGS_FROM_IRC_BUFFER {
@@ -61,11 +378,11 @@ pub async fn mark_message_from_irc(slack_channel_id: &str, msg_ts: String) {
 // Returns true if the message was really sent from IRC
 pub async fn ack_message_from_irc(slack_channel_id: &str, msg_ts: &str) -> bool {
     let channel_msgs_guard = GLOBAL_CHANNELS_MSGS_FROM_IRC.read().await;
-    let mut msgs_guard = channel_msgs_guard
-        .get(slack_channel_id)
-        .unwrap()
-        .write()
-        .await;
+    // Conversations created after we connected, like a brand new IM, have nothing from IRC yet
+    let mut msgs_guard = match channel_msgs_guard.get(slack_channel_id) {
+        Some(msgs) => msgs.write().await,
+        None => return false,
+    };
 
     let pos = match msgs_guard.iter().position(|x| *x == *msg_ts) {
         Some(x) => x,
@@ -1,31 +1,78 @@
-use crate::channels::{get_channel_id, mark_message_from_irc, register_channel};
+use crate::channels::{
+    get_bridged_channels, get_channel_id, get_im_channel_id, get_irc_channel_name,
+    get_irc_channels, irc_channel_name, is_bridged_for_client, join_virtual_user,
+    mark_message_from_irc, part_virtual_user, register_channel, register_im,
+    release_irc_channel_name, reserve_irc_channel_name, set_channel_topic, unregister_channel,
+    unregister_client,
+};
+use crate::formatting::{
+    irc_to_slack, parse_ctcp_action, parse_reaction_shorthand, slack_reaction_name, slack_to_irc,
+};
+use crate::history::{backfill_channel, handle_chathistory, HistoryTarget};
+use crate::messages::{
+    find_thread_ts, forget_posted_messages, get_last_message, parse_msgid, parse_thread_prefix,
+    pop_posted_message, remember_message, remember_posted_message,
+};
 use crate::settings::UserProfile;
 use crate::settings::GLOBAL_SETTINGS;
-use crate::slack::Slack;
-use crate::users::register_username;
+use crate::slack::{Channel as SlackChannel, Slack, UserInfo};
+use crate::uploads::{
+    parse_dcc_send, upload_from_dcc, upload_from_url, UploadTarget, UPLOAD_COMMAND,
+};
+use crate::users::{
+    format_status, get_away_message, get_user_id, get_user_info, get_username, make_virtual_user,
+    register_user,
+};
 use futures::executor::block_on;
-use rirc_server::{Channel as IRCChannel, Client as IRCClient, Message as IRCMsg, ServerCallbacks};
+use rirc_server::{
+    Channel as IRCChannel, Client as IRCClient, ClientSender as IRCClientSender, Message as IRCMsg,
+    ServerCallbacks,
+};
 use std::collections::HashMap;
 use std::error::Error;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 
+pub static IRC_SERVER_NAME: &str = "rIRC-slack-gateway";
+
+// Sending this in a channel deletes your last message there, like in other Slack clients
+const DELETE_LAST_MSG_COMMAND: &str = "s///";
+
 lazy_static! {
     pub static ref GLOBAL_CLIENTS: Arc<RwLock<HashMap<SocketAddr, Client>>> =
         Arc::new(RwLock::new(HashMap::new()));
 }
 
+#[derive(Clone)]
 pub struct Client {
     pub addr: SocketAddr,
+    pub nick: String,
+    pub irc_sender: IRCClientSender,
+    pub team_id: String,
+    pub user_id: String,
     pub slack: Arc<Slack>,
+    pub server_time: bool, // Capabilities that change how history is replayed
+    pub batch: bool,
 }
 
 impl Client {
-    fn new(irc_client: &IRCClient, profile: &UserProfile) -> Client {
-        Client {
+    fn new(
+        irc_client: &IRCClient,
+        nick: String,
+        profile: &UserProfile,
+    ) -> Result<Client, Box<dyn Error + Send + Sync>> {
+        let slack = Slack::new(&profile.slack_token);
+        let auth_info = block_on(slack.test_auth())?;
+        Ok(Client {
             addr: irc_client.addr.to_owned(),
-            slack: Arc::new(Slack::new(&profile.slack_token)),
-        }
+            nick,
+            irc_sender: irc_client.get_sender(),
+            team_id: auth_info.team_id,
+            user_id: auth_info.user_id,
+            slack: Arc::new(slack),
+            server_time: irc_client.has_cap("server-time"),
+            batch: irc_client.has_cap("batch"),
+        })
     }
 }
 
@@ -46,12 +93,74 @@ fn on_client_registering(irc_client: &mut IRCClient) -> Result<bool, Box<dyn Err
 
     println!("Registering: {} ({})", irc_client.addr, nick);
 
+    let client = Client::new(irc_client, nick, profile)?;
     let mut clients = GLOBAL_CLIENTS.write().unwrap();
-    clients.insert(irc_client.addr.to_owned(), Client::new(irc_client, profile));
+    clients.insert(irc_client.addr.to_owned(), client);
 
     Ok(true)
 }
 
+// Bridges the IMs of every client in the team, for when a message arrives in an IM we don't know
+pub async fn refresh_ims(team_id: &str) {
+    let team_clients = {
+        let clients = GLOBAL_CLIENTS.read().unwrap();
+        clients
+            .values()
+            .filter(|client| client.team_id == team_id)
+            .map(|client| (client.addr, client.slack.clone()))
+            .collect::<Vec<_>>()
+    };
+
+    for (client_addr, slack) in team_clients {
+        register_ims(client_addr, &slack).await;
+    }
+}
+
+async fn register_ims(client_addr: SocketAddr, slack: &Slack) {
+    match slack.conversations_list("im").await {
+        Ok(ims) => {
+            for im in ims {
+                if let Some(user) = im.user {
+                    register_im(im.id, user, client_addr).await;
+                }
+            }
+        }
+        Err(err) => println!("Couldn't list IMs of {}: {}", client_addr, err),
+    }
+}
+
+pub fn is_connected_user(slack_user_id: &str) -> bool {
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    clients
+        .values()
+        .any(|client| client.user_id == slack_user_id)
+}
+
+// Shows the Slack members of a channel as virtual users, so NAMES and WHO match Slack
+async fn add_channel_members(
+    slack: &Slack,
+    channel_id: &str,
+    irc_chan: &tokio::sync::RwLock<IRCChannel>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let members = slack.conversations_members(channel_id).await?;
+
+    // Users connected to the gateway are already in the channel as real IRC clients
+    let members = members
+        .into_iter()
+        .filter(|member| !is_connected_user(member))
+        .collect::<Vec<_>>();
+    let mut irc_chan_guard = irc_chan.write().await;
+    for member in members {
+        let user_info = match get_user_info(&member) {
+            Some(user_info) if !user_info.deleted => user_info,
+            _ => continue,
+        };
+        join_virtual_user(&mut irc_chan_guard, &user_info).await;
+    }
+
+    Ok(())
+}
+
 fn on_client_registered(irc_client: &IRCClient) -> Result<(), Box<dyn Error + Send + Sync>> {
     let clients = GLOBAL_CLIENTS.read().unwrap();
     let client = match clients.get(&irc_client.addr) {
@@ -59,37 +168,572 @@ fn on_client_registered(irc_client: &IRCClient) -> Result<(), Box<dyn Error + Se
         _ => return Err(From::from("Client just registered, but isn't in our list!")),
     };
 
+    let conversations = block_on(
+        client
+            .slack
+            .conversations_list("public_channel,private_channel,mpim,im"),
+    )?;
+    let (ims, channels): (Vec<_>, Vec<_>) = conversations.into_iter().partition(|c| c.is_im);
+
+    for im in ims {
+        if let Some(user) = im.user {
+            block_on(register_im(im.id, user, client.addr));
+        }
+    }
+
+    // We only get to see the private channels and mpims we're in
+    let mut bridged_channels = Vec::new();
+    channels
+        .into_iter()
+        .filter(|c| c.is_member || c.is_private)
+        .for_each(|channel| {
+            let irc_chan_name = match irc_channel_name(&channel) {
+                Some(irc_chan_name) => irc_chan_name,
+                None => return,
+            };
+            if let Some(irc_chan) =
+                block_on(join_bridged_channel(client, &channel.id, &irc_chan_name))
+            {
+                bridged_channels.push((channel, irc_chan));
+            }
+        });
+
     let slack = client.slack.clone();
+    let history_target = HistoryTarget::new(client);
     tokio::spawn(async move {
         if let Ok(users_list) = slack.users_list().await {
-            users_list.into_iter().for_each(|user_info| {
-                register_username(user_info.id, user_info.name);
-            });
+            users_list.into_iter().for_each(register_user);
+        }
+
+        // The user is now in their channels as a real IRC client, not a virtual user
+        if let Some(username) = get_username(&history_target.user_id) {
+            for irc_chan in get_irc_channels(&history_target.team_id).await {
+                let mut irc_chan_guard = irc_chan.write().await;
+                part_virtual_user(&mut irc_chan_guard, &username).await;
+            }
+        }
+
+        for (channel, irc_chan) in bridged_channels {
+            init_bridged_channel(&slack, &history_target, &channel, &irc_chan).await;
         }
     });
 
-    block_on(client.slack.channels_list())?
-        .into_iter()
-        .filter(|c| c.is_member)
-        .for_each(|channel| {
-            let irc_chan_name = "#".to_owned() + &channel.name;
-            let _ = block_on(irc_client.join(&irc_chan_name));
-            let client_channels_guard = block_on(irc_client.channels.read());
-            if let Some(irc_chan) = client_channels_guard.get(&irc_chan_name.to_ascii_uppercase()) {
-                block_on(register_channel(
-                    channel.id,
-                    irc_chan.upgrade().unwrap(),
-                ));
+    Ok(())
+}
+
+// Brings a freshly bridged IRC channel up to date with its members, topic and recent messages
+async fn init_bridged_channel(
+    slack: &Slack,
+    history_target: &HistoryTarget,
+    channel: &SlackChannel,
+    irc_chan: &tokio::sync::RwLock<IRCChannel>,
+) {
+    if let Err(err) = add_channel_members(slack, &channel.id, irc_chan).await {
+        println!("Couldn't add members of channel {}: {}", channel.id, err);
+    }
+
+    if let Some(topic) = channel.topic_or_purpose() {
+        let setter = get_username(&topic.creator).unwrap_or_else(|| topic.creator.clone());
+        let topic_text = slack_to_irc(&topic.value).await;
+        let mut irc_chan_guard = irc_chan.write().await;
+        set_channel_topic(&mut irc_chan_guard, &setter, topic_text).await;
+    }
+
+    let irc_chan_name = irc_chan.read().await.name.clone();
+    if let Err(err) = backfill_channel(slack, history_target, &channel.id, &irc_chan_name).await {
+        println!(
+            "Couldn't backfill history of channel {}: {}",
+            channel.id, err
+        );
+    }
+}
+
+// Joins the IRC channel and bridges it with the Slack channel, returns None if that failed
+async fn join_bridged_channel(
+    client: &Client,
+    slack_channel_id: &str,
+    irc_chan_name: &str,
+) -> Option<Arc<tokio::sync::RwLock<IRCChannel>>> {
+    let irc_chan_name =
+        reserve_irc_channel_name(&client.team_id, slack_channel_id, irc_chan_name).await;
+    let irc_chan = match client.irc_sender.join(&irc_chan_name).await {
+        Ok(irc_chan) => irc_chan,
+        Err(err) => {
+            println!("Couldn't join channel {}: {}", irc_chan_name, err);
+            release_irc_channel_name(&client.team_id, slack_channel_id, &irc_chan_name).await;
+            return None;
+        }
+    };
+    match register_channel(
+        client.team_id.clone(),
+        slack_channel_id.to_owned(),
+        client.addr,
+        irc_chan.clone(),
+    )
+    .await
+    {
+        Ok(()) => Some(irc_chan),
+        Err(err) => {
+            println!("Couldn't bridge channel {}: {}", irc_chan_name, err);
+            None
+        }
+    }
+}
+
+// The connected clients of a Slack user
+fn get_user_clients(team_id: &str, user_id: &str) -> Vec<Client> {
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    clients
+        .values()
+        .filter(|client| client.team_id == team_id && client.user_id == user_id)
+        .cloned()
+        .collect()
+}
+
+// Bridges a channel the user joined from Slack for their IRC clients, which join it too
+pub async fn bridge_joined_channel(team_id: &str, user_id: &str, slack_channel_id: &str) {
+    for client in get_user_clients(team_id, user_id) {
+        // Joins from IRC are bridged already by the time Slack tells us about them
+        if is_bridged_for_client(team_id, slack_channel_id, &client.addr).await {
+            continue;
+        }
+        let channel = match client.slack.conversations_info(slack_channel_id).await {
+            Ok(channel) => channel,
+            Err(err) => {
+                println!("Couldn't get channel {}: {}", slack_channel_id, err);
+                continue;
             }
+        };
+        let irc_chan_name = match irc_channel_name(&channel) {
+            Some(irc_chan_name) => irc_chan_name,
+            None => continue,
+        };
+        if let Some(irc_chan) = join_bridged_channel(&client, &channel.id, &irc_chan_name).await {
+            let history_target = HistoryTarget::new(&client);
+            init_bridged_channel(&client.slack, &history_target, &channel, &irc_chan).await;
+        }
+    }
+}
+
+// Unbridges a channel the user left from Slack for their IRC clients, which part it too
+pub async fn unbridge_left_channel(team_id: &str, user_id: &str, slack_channel_id: &str) {
+    let irc_chan_name = match get_irc_channel_name(slack_channel_id).await {
+        Some(irc_chan_name) => irc_chan_name,
+        None => return,
+    };
+    for client in get_user_clients(team_id, user_id) {
+        if !is_bridged_for_client(team_id, slack_channel_id, &client.addr).await {
+            continue;
+        }
+        unregister_channel(team_id, slack_channel_id, &client.addr).await;
+        let _ = client
+            .irc_sender
+            .part(&irc_chan_name, Some("Left the channel on Slack"))
+            .await;
+    }
+}
+
+fn on_client_join(
+    irc_client: &IRCClient,
+    irc_chan_name: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    let client = match clients.get(&irc_client.addr) {
+        Some(client) => client,
+        _ => {
+            return Err(From::from(
+                "Client joined a channel, but isn't in our list!",
+            ))
+        }
+    };
+
+    // Slack channel names are always lowercase
+    let slack_chan_name = match irc_chan_name.strip_prefix('#') {
+        Some(name) => name.to_lowercase(),
+        None => return Err(From::from("Only #channels can be joined on Slack")),
+    };
+
+    // Channels of a team can be bridged under another name when a different team has it
+    let bridged_channel_id = block_on(get_channel_id(&client.team_id, irc_chan_name));
+    let existing_channel = block_on(
+        client
+            .slack
+            .conversations_list("public_channel,private_channel"),
+    )?
+    .into_iter()
+    .find(|c| match &bridged_channel_id {
+        Some(channel_id) => c.id == *channel_id,
+        None => c.name.as_deref() == Some(slack_chan_name.as_str()),
+    });
+    let channel = match existing_channel {
+        Some(channel) if channel.is_member => channel,
+        Some(channel) => block_on(client.slack.conversations_join(&channel.id))?,
+        None => block_on(client.slack.conversations_create(&slack_chan_name))?,
+    };
+
+    let irc_chan_name = match irc_channel_name(&channel) {
+        Some(irc_chan_name) => irc_chan_name,
+        None => return Err(From::from("Joined a Slack conversation without a name")),
+    };
+    if let Some(irc_chan) = block_on(join_bridged_channel(client, &channel.id, &irc_chan_name)) {
+        let slack = client.slack.clone();
+        let history_target = HistoryTarget::new(client);
+        tokio::spawn(async move {
+            init_bridged_channel(&slack, &history_target, &channel, &irc_chan).await;
         });
+    }
 
-    Ok(())
+    // We just joined the IRC channel ourselves
+    Ok(false)
+}
+
+fn on_client_part(
+    irc_client: &IRCClient,
+    chan: &IRCChannel,
+    _msg: &IRCMsg,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    let client = match clients.get(&irc_client.addr) {
+        Some(client) => client,
+        _ => return Err(From::from("Client left a channel, but isn't in our list!")),
+    };
+
+    let channel_id = match block_on(get_channel_id(&client.team_id, &chan.name)) {
+        Some(channel_id) => channel_id,
+        None => return Ok(true),
+    };
+    block_on(client.slack.conversations_leave(&channel_id))?;
+    block_on(unregister_channel(
+        &client.team_id,
+        &channel_id,
+        &client.addr,
+    ));
+
+    Ok(true)
+}
+
+fn on_client_topic(
+    irc_client: &IRCClient,
+    chan: &IRCChannel,
+    topic: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    let client = match clients.get(&irc_client.addr) {
+        Some(client) => client,
+        _ => return Err(From::from("Client set a topic, but isn't in our list!")),
+    };
+
+    let channel_id = match block_on(get_channel_id(&client.team_id, &chan.name)) {
+        Some(channel_id) => channel_id,
+        None => {
+            return Err(From::from(
+                "Couldn't find matching Slack channel for IRC topic",
+            ))
+        }
+    };
+    let topic = block_on(irc_to_slack(&client.team_id, topic));
+    block_on(client.slack.conversations_set_topic(&channel_id, &topic))?;
+
+    Ok(true)
+}
+
+fn on_client_away(
+    irc_client: &IRCClient,
+    away_message: Option<&str>,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    let client = match clients.get(&irc_client.addr) {
+        Some(client) => client,
+        _ => return Err(From::from("Client set away, but isn't in our list!")),
+    };
+
+    // An empty status clears the one set by the previous AWAY
+    let presence = if away_message.is_some() {
+        "away"
+    } else {
+        "auto"
+    };
+    block_on(client.slack.users_set_presence(presence))?;
+    block_on(
+        client
+            .slack
+            .users_profile_set_status(away_message.unwrap_or(""), ""),
+    )?;
+
+    Ok(true)
+}
+
+fn on_client_whois(
+    irc_client: &IRCClient,
+    target: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let (nick, irc_sender) = {
+        let clients = GLOBAL_CLIENTS.read().unwrap();
+        match clients.get(&irc_client.addr) {
+            Some(client) => (client.nick.clone(), client.irc_sender.clone()),
+            _ => return Err(From::from("Client sent a WHOIS, but isn't in our list!")),
+        }
+    };
+
+    // Let the IRC server answer for anyone who isn't on Slack
+    let user_info = match get_user_id(target).and_then(|user_id| get_user_info(&user_id)) {
+        Some(user_info) => user_info,
+        None => return Ok(true),
+    };
+
+    for reply in whois_replies(&nick, &user_info) {
+        block_on(irc_sender.send(reply))?;
+    }
+
+    Ok(false)
+}
+
+fn whois_replies(nick: &str, user_info: &UserInfo) -> Vec<IRCMsg> {
+    let virtual_user = make_virtual_user(user_info);
+    let profile = &user_info.profile;
+
+    let mut replies = vec![
+        (
+            "311",
+            vec![
+                virtual_user.user,
+                virtual_user.host,
+                "*".to_owned(),
+                virtual_user.realname,
+            ],
+        ),
+        (
+            "312",
+            vec![
+                IRC_SERVER_NAME.to_owned(),
+                "Slack workspace ".to_owned() + &user_info.team_id,
+            ],
+        ),
+    ];
+    if let Some(away_message) = get_away_message(&user_info.id) {
+        replies.push(("301", vec![away_message]));
+    }
+
+    // 320 is the usual numeric for free-form WHOIS lines
+    let mut info = Vec::new();
+    if !profile.title.is_empty() {
+        info.push("is ".to_owned() + &profile.title);
+    }
+    if let Some(tz) = &user_info.tz {
+        let tz_label = user_info.tz_label.as_deref().unwrap_or(tz);
+        info.push(format!("has timezone {} ({})", tz_label, tz));
+    }
+    let status = format_status(user_info);
+    if !status.is_empty() {
+        info.push("has status: ".to_owned() + &status);
+    }
+    if let Some(email) = &profile.email {
+        info.push("has email ".to_owned() + email);
+    }
+    if let Some(avatar) = &profile.image_192 {
+        info.push("has avatar ".to_owned() + avatar);
+    }
+    if user_info.is_owner {
+        info.push("is a workspace owner".to_owned());
+    } else if user_info.is_admin {
+        info.push("is a workspace admin".to_owned());
+    }
+    if user_info.is_bot || user_info.is_app_user {
+        info.push("is a bot".to_owned());
+    }
+    if user_info.deleted {
+        info.push("has been deactivated".to_owned());
+    }
+    info.push("is Slack user ".to_owned() + &user_info.id);
+    replies.extend(info.into_iter().map(|line| ("320", vec![line])));
+
+    replies.push(("318", vec!["End of /WHOIS list".to_owned()]));
+
+    replies
+        .into_iter()
+        .map(|(command, params)| {
+            let mut full_params = vec![nick.to_owned(), user_info.name.clone()];
+            full_params.extend(params);
+            IRCMsg {
+                tags: Vec::new(),
+                source: Some(IRC_SERVER_NAME.to_owned()),
+                command: command.to_owned(),
+                params: full_params,
+            }
+        })
+        .collect()
+}
+
+fn on_client_list(
+    irc_client: &IRCClient,
+    msg: &IRCMsg,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let (nick, irc_sender, slack) = {
+        let clients = GLOBAL_CLIENTS.read().unwrap();
+        match clients.get(&irc_client.addr) {
+            Some(client) => (
+                client.nick.clone(),
+                client.irc_sender.clone(),
+                client.slack.clone(),
+            ),
+            _ => return Err(From::from("Client sent a LIST, but isn't in our list!")),
+        }
+    };
+
+    // LIST can be restricted to a comma-separated list of channels
+    let wanted_names = msg.params.first().map(|names| {
+        names
+            .split(',')
+            .map(|name| name.to_lowercase())
+            .collect::<Vec<_>>()
+    });
+
+    let channels = block_on(slack.conversations_list("public_channel,private_channel,mpim"))?;
+
+    let mut replies = vec![("321", vec!["Channel".to_owned(), "Users  Name".to_owned()])];
+    for channel in channels {
+        let bridged_name = block_on(get_irc_channel_name(&channel.id));
+        let irc_chan_name = match bridged_name.or_else(|| irc_channel_name(&channel)) {
+            Some(irc_chan_name) => irc_chan_name,
+            None => continue,
+        };
+        if let Some(wanted_names) = &wanted_names {
+            if !wanted_names.contains(&irc_chan_name.to_lowercase()) {
+                continue;
+            }
+        }
+        let topic = match channel.topic_or_purpose() {
+            Some(topic) => block_on(slack_to_irc(&topic.value)).replace('\n', " "),
+            None => String::new(),
+        };
+        let num_members = channel.num_members.unwrap_or_default().to_string();
+        replies.push(("322", vec![irc_chan_name, num_members, topic]));
+    }
+    replies.push(("323", vec!["End of /LIST".to_owned()]));
+
+    for (command, params) in replies {
+        let mut full_params = vec![nick.clone()];
+        full_params.extend(params);
+        block_on(irc_sender.send(IRCMsg {
+            tags: Vec::new(),
+            source: Some(IRC_SERVER_NAME.to_owned()),
+            command: command.to_owned(),
+            params: full_params,
+        }))?;
+    }
+
+    Ok(false)
+}
+
+fn on_client_chathistory(
+    irc_client: &IRCClient,
+    msg: &IRCMsg,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let (history_target, team_id, slack) = {
+        let clients = GLOBAL_CLIENTS.read().unwrap();
+        match clients.get(&irc_client.addr) {
+            Some(client) => (
+                HistoryTarget::new(client),
+                client.team_id.clone(),
+                client.slack.clone(),
+            ),
+            _ => {
+                return Err(From::from(
+                    "Client sent a CHATHISTORY, but isn't in our list!",
+                ))
+            }
+        }
+    };
+
+    block_on(handle_chathistory(
+        &slack,
+        &history_target,
+        &team_id,
+        &msg.params,
+    ))?;
+
+    Ok(false)
+}
+
+// Reactions are sent as TAGMSGs with a +draft/react tag, replying to the message they react to
+fn on_client_tagmsg(
+    irc_client: &IRCClient,
+    _target: &str,
+    msg: &IRCMsg,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let tag_value = |key: &str| {
+        msg.tags
+            .iter()
+            .find_map(|tag| tag.strip_prefix(key)?.strip_prefix('='))
+    };
+    let (reaction, reply_msgid) = match (tag_value("+draft/react"), tag_value("+draft/reply")) {
+        (Some(reaction), Some(reply_msgid)) => (reaction, reply_msgid),
+        _ => return Ok(true),
+    };
+    let reaction = match slack_reaction_name(reaction) {
+        Some(reaction) => reaction,
+        None => return Err(From::from("Slack has no name for this reaction")),
+    };
+    let (channel_id, ts) = match parse_msgid(reply_msgid) {
+        Some(ids) => ids,
+        None => return Err(From::from("Can only react to messages from Slack")),
+    };
+
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    let client = match clients.get(&irc_client.addr) {
+        Some(client) => client,
+        _ => return Err(From::from("Client sent a TAGMSG, but isn't in our list!")),
+    };
+    block_on(client.slack.reactions_add(channel_id, ts, &reaction))?;
+
+    // Slack sends the reaction back to everyone, including this client
+    Ok(false)
+}
+
+// Adds a user whose last client disconnected back to the bridged channels they're a member of
+async fn rejoin_virtual_user(team_id: String, user_id: String) {
+    let user_info = match get_user_info(&user_id) {
+        Some(user_info) if !user_info.deleted => user_info,
+        _ => return,
+    };
+    // Channels stay bridged only while other clients of the team are in them, use one of those
+    let slack = {
+        let clients = GLOBAL_CLIENTS.read().unwrap();
+        match clients.values().find(|client| client.team_id == team_id) {
+            Some(client) => client.slack.clone(),
+            None => return,
+        }
+    };
+
+    for (slack_channel_id, irc_chan) in get_bridged_channels(&team_id).await {
+        match slack.conversations_members(&slack_channel_id).await {
+            Ok(members) if members.contains(&user_id) => {
+                let mut irc_chan_guard = irc_chan.write().await;
+                join_virtual_user(&mut irc_chan_guard, &user_info).await;
+            }
+            Ok(_) => (),
+            Err(err) => println!(
+                "Couldn't get members of channel {}: {}",
+                slack_channel_id, err
+            ),
+        }
+    }
 }
 
 fn on_client_disconnect(addr: &SocketAddr) -> Result<(), Box<dyn Error + Send + Sync>> {
     println!("Disconnected: {}", addr);
-    let mut clients = GLOBAL_CLIENTS.write().unwrap();
-    clients.remove(addr);
+    // Don't hold the clients lock while waiting on channel locks, channel inits take them the
+    // other way around
+    let client = GLOBAL_CLIENTS.write().unwrap().remove(addr);
+    block_on(unregister_client(addr));
+    block_on(forget_posted_messages(addr));
+
+    // The user goes back to being a virtual user in the channels they're still a member of
+    if let Some(client) = client {
+        if get_user_clients(&client.team_id, &client.user_id).is_empty() {
+            tokio::spawn(rejoin_virtual_user(client.team_id, client.user_id));
+        }
+    }
 
     Ok(())
 }
@@ -107,7 +751,13 @@ fn on_client_channel_message(
         .collect::<Vec<&str>>()
         .join(" ");
 
-    let channel_id = match block_on(get_channel_id(&chan.name)) {
+    let clients = GLOBAL_CLIENTS.read().unwrap();
+    let client = match clients.get(&client.addr) {
+        Some(client) => client,
+        _ => return Err(From::from("Client sent message, but isn't in our list!")),
+    };
+
+    let channel_id = match block_on(get_channel_id(&client.team_id, &chan.name)) {
         Some(channel_id) => channel_id,
         None => {
             return Err(From::from(
@@ -116,16 +766,136 @@ fn on_client_channel_message(
         }
     };
 
+    post_message_from_irc(client, &channel_id, &msg_text)
+}
+
+fn on_client_user_message(
+    client: &IRCClient,
+    target_nick: &str,
+    msg: &IRCMsg,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let msg_text = msg
+        .params
+        .iter()
+        .skip(1)
+        .map(|s| &**s)
+        .collect::<Vec<&str>>()
+        .join(" ");
+
     let clients = GLOBAL_CLIENTS.read().unwrap();
     let client = match clients.get(&client.addr) {
         Some(client) => client,
         _ => return Err(From::from("Client sent message, but isn't in our list!")),
     };
 
-    let msg_ts = block_on(client.slack.post_message(&channel_id, &msg_text))?;
+    let user_id = match get_user_id(target_nick) {
+        Some(user_id) => user_id,
+        None => {
+            return Err(From::from(
+                "Couldn't find matching Slack user for IRC message",
+            ))
+        }
+    };
+
+    let channel_id = match block_on(get_im_channel_id(&client.addr, &user_id)) {
+        Some(channel_id) => channel_id,
+        None => {
+            let channel_id = block_on(client.slack.conversations_open(&user_id))?;
+            block_on(register_im(channel_id.clone(), user_id, client.addr));
+            channel_id
+        }
+    };
+
+    post_message_from_irc(client, &channel_id, &msg_text)?;
+
+    // Slack delivers IMs to the other side, even if they're connected to this gateway
+    Ok(false)
+}
+
+fn post_message_from_irc(
+    client: &Client,
+    channel_id: &str,
+    msg_text: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    if msg_text == DELETE_LAST_MSG_COMMAND {
+        let msg_ts = match block_on(pop_posted_message(&client.addr, channel_id)) {
+            Some(msg_ts) => msg_ts,
+            None => {
+                return Err(From::from(
+                    "No message of yours left to delete in this channel",
+                ))
+            }
+        };
+        block_on(client.slack.delete_message(channel_id, &msg_ts))?;
+
+        // Slack will tell everyone about the deletion, this command shouldn't be relayed
+        return Ok(false);
+    }
+
+    if let Some(offer) = parse_dcc_send(msg_text) {
+        let target = UploadTarget::new(client, channel_id, None);
+        tokio::spawn(upload_from_dcc(target, client.addr.ip(), offer));
+        return Ok(false);
+    }
+
+    if let Some(reaction) = parse_reaction_shorthand(msg_text) {
+        let last_msg = match block_on(get_last_message(channel_id)) {
+            Some(last_msg) => last_msg,
+            None => return Err(From::from("No recent message to react to in this channel")),
+        };
+        block_on(
+            client
+                .slack
+                .reactions_add(channel_id, &last_msg.ts, &reaction),
+        )?;
+        return Ok(false);
+    }
+
+    // Slack's /me messages can't be sent in threads
+    if let Some(action) = parse_ctcp_action(msg_text) {
+        let slack_action = block_on(irc_to_slack(&client.team_id, action));
+        let msg_ts = block_on(client.slack.me_message(channel_id, &slack_action))?;
+        block_on(mark_message_from_irc(channel_id, msg_ts.clone()));
+        block_on(remember_message(channel_id, &msg_ts, None, action));
+        block_on(remember_posted_message(client.addr, channel_id, msg_ts));
+        return Ok(true);
+    }
+
+    let (msg_text, thread_ts, reply_broadcast) = match parse_thread_prefix(msg_text) {
+        Some((thread_ref, reply_broadcast, text)) => {
+            match block_on(find_thread_ts(channel_id, thread_ref)) {
+                Some(thread_ts) => (text, Some(thread_ts), reply_broadcast),
+                None => return Err(From::from("Unknown or expired thread reference")),
+            }
+        }
+        None => (msg_text, None, false),
+    };
+
+    if let Some(url) = msg_text.strip_prefix(UPLOAD_COMMAND) {
+        let target = UploadTarget::new(client, channel_id, thread_ts);
+        tokio::spawn(upload_from_url(target, url.trim().to_owned()));
+        return Ok(false);
+    }
+
+    let slack_text = block_on(irc_to_slack(&client.team_id, msg_text));
+
+    let msg_ts = block_on(client.slack.post_message(
+        channel_id,
+        &slack_text,
+        thread_ts.as_deref(),
+        reply_broadcast,
+    ))?;
 
     // Mark the message we just sent as coming from IRC, so we ignore it when Slack sends it back
-    block_on(mark_message_from_irc(&channel_id, msg_ts));
+    block_on(mark_message_from_irc(channel_id, msg_ts.clone()));
+    // Slack won't relay it to us, remember it ourselves for reactions and threads
+    block_on(remember_message(
+        channel_id,
+        &msg_ts,
+        thread_ts.as_deref(),
+        msg_text,
+    ));
+    block_on(remember_posted_message(client.addr, channel_id, msg_ts));
     Ok(true)
 }
 
@@ -135,6 +905,15 @@ pub fn get_server_callbacks() -> ServerCallbacks {
         on_client_registered,
         on_client_disconnect,
         on_client_channel_message,
+        on_client_user_message,
+        on_client_join,
+        on_client_part,
+        on_client_topic,
+        on_client_away,
+        on_client_whois,
+        on_client_list,
+        on_client_chathistory,
+        on_client_tagmsg,
         ..Default::default()
     }
 }
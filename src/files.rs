@@ -0,0 +1,171 @@
+use crate::settings::{SlackAppTransport, GLOBAL_SETTINGS};
+use crate::slack::File as SlackFile;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::sync::RwLock;
+
+// Max number of shared files we can still proxy downloads of, older ones are forgotten
+const MAX_SHARED_FILES: usize = 1024;
+
+// Download links are prefixed with this, the Slack app listener serves them
+pub const FILE_DOWNLOAD_PATH: &str = "/files/";
+
+#[derive(Clone)]
+pub struct SharedFile {
+    pub team_id: String, // Any client of the team can download it for us
+    pub download_url: String,
+    pub mimetype: Option<String>,
+}
+
+lazy_static! {
+    // File IDs to the files recently shared in Slack, oldest first
+    static ref GLOBAL_SHARED_FILES: RwLock<VecDeque<(String, SharedFile)>> =
+        RwLock::new(VecDeque::new());
+}
+
+pub fn get_shared_file(file_id: &str) -> Option<SharedFile> {
+    let files_guard = GLOBAL_SHARED_FILES.read().expect("Shared files read lock");
+    files_guard
+        .iter()
+        .find(|(id, _)| id == file_id)
+        .map(|(_, file)| file.clone())
+}
+
+fn remember_shared_file(file_id: &str, shared_file: SharedFile) {
+    let mut files_guard = GLOBAL_SHARED_FILES
+        .write()
+        .expect("Shared files write lock");
+    files_guard.retain(|(id, _)| id != file_id);
+    files_guard.push_back((file_id.to_owned(), shared_file));
+    if files_guard.len() > MAX_SHARED_FILES {
+        files_guard.pop_front();
+    }
+}
+
+// Download links are signed with the app's signing secret, so they can't be guessed
+fn file_link_signature(file_id: &str) -> Option<String> {
+    let settings = GLOBAL_SETTINGS.read().unwrap();
+    if settings.slack_app_signing_secret.is_empty() {
+        return None;
+    }
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(settings.slack_app_signing_secret.as_bytes()).unwrap();
+    mac.update(b"file:");
+    mac.update(file_id.as_bytes());
+    Some(hex::encode(&mac.finalize().into_bytes()[..16]))
+}
+
+// Compares in constant time, so the signature can't be guessed one character at a time
+pub fn verify_file_link_signature(file_id: &str, signature: &str) -> bool {
+    match file_link_signature(file_id) {
+        Some(expected) => {
+            expected.len() == signature.len()
+                && expected
+                    .bytes()
+                    .zip(signature.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        }
+        None => false,
+    }
+}
+
+// A link IRC users can open without being logged in to Slack, if the gateway can serve it
+fn file_link(team_id: &str, file: &SlackFile) -> Option<String> {
+    let public_url = {
+        let settings = GLOBAL_SETTINGS.read().unwrap();
+        if settings.slack_app_transport != SlackAppTransport::Http
+            || settings.slack_app_public_url.is_empty()
+        {
+            return None;
+        }
+        settings
+            .slack_app_public_url
+            .trim_end_matches('/')
+            .to_owned()
+    };
+    let download_url = file.url_private_download.clone()?;
+    let signature = file_link_signature(&file.id)?;
+
+    remember_shared_file(
+        &file.id,
+        SharedFile {
+            team_id: team_id.to_owned(),
+            download_url,
+            mimetype: file.mimetype.clone(),
+        },
+    );
+    let name = file.name.as_deref().unwrap_or(&file.id);
+    Some(format!(
+        "{}{}{}/{}/{}",
+        public_url,
+        FILE_DOWNLOAD_PATH,
+        file.id,
+        signature,
+        percent_encode(name)
+    ))
+}
+
+// Adds a line describing each of the message's files after its text
+pub fn append_file_descriptions(team_id: &str, text: String, files: &[SlackFile]) -> String {
+    files.iter().fold(text, |text, file| {
+        let description = describe_file(team_id, file);
+        if text.is_empty() {
+            description
+        } else {
+            text + "\n" + &description
+        }
+    })
+}
+
+// Shows a shared file as "[file] title (size, type): link"
+fn describe_file(team_id: &str, file: &SlackFile) -> String {
+    let title = file
+        .title
+        .as_deref()
+        .or(file.name.as_deref())
+        .unwrap_or("untitled");
+    let mut details = Vec::new();
+    if let Some(size) = file.size {
+        details.push(format_size(size));
+    }
+    if let Some(pretty_type) = &file.pretty_type {
+        details.push(pretty_type.clone());
+    }
+
+    let mut description = "[file] ".to_owned() + title;
+    if !details.is_empty() {
+        description += &format!(" ({})", details.join(", "));
+    }
+    if let Some(link) = file_link(team_id, file).or_else(|| file.permalink.clone()) {
+        description += &(": ".to_owned() + &link);
+    }
+    description
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// File names go at the end of the link so IRC users see them, they can have any character
+fn percent_encode(name: &str) -> String {
+    name.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
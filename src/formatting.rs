@@ -0,0 +1,465 @@
+use crate::channels::{get_channel_id, get_irc_channel_name};
+use crate::users::{get_user_id, get_username};
+
+// IRC formatting control codes
+const BOLD: &str = "\x02";
+const ITALIC: &str = "\x1d";
+const STRIKETHROUGH: &str = "\x1e";
+const MONOSPACE: &str = "\x11";
+const NEWLINE_MONOSPACE: &str = "\n\x11";
+const UNDERLINE: char = '\x1f';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const REVERSE: char = '\x16';
+const RESET: char = '\x0f';
+
+// Converts Slack's mrkdwn and entities (mentions, links, HTML escapes) to IRC text
+pub async fn slack_to_irc(text: &str) -> String {
+    replace_entities(&apply_mrkdwn(text)).await
+}
+
+// Replaces Slack's *bold*, _italic_, ~strike~ and `code` markers with IRC control codes
+fn apply_mrkdwn(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    // What to replace each char with, if it's a marker
+    let mut replacements: Vec<Option<&str>> = vec![None; chars.len()];
+
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            // Entities are opaque, markers in URLs or mentions aren't formatting
+            '<' => i = find_from(&chars, i, '>').unwrap_or(i),
+            '`' if chars[i..].starts_with(&['`', '`', '`']) => {
+                if let Some(end) = find_code_block_end(&chars, i + 3) {
+                    replacements[i..i + 3].copy_from_slice(&[Some(MONOSPACE), Some(""), Some("")]);
+                    replacements[end..end + 3].copy_from_slice(&[Some(""), Some(""), Some("")]);
+                    // IRC formatting doesn't carry over to the next line
+                    for (pos, c) in chars.iter().enumerate().take(end).skip(i + 3) {
+                        if *c == '\n' {
+                            replacements[pos] = Some(NEWLINE_MONOSPACE);
+                        }
+                    }
+                    i = end + 2;
+                } else {
+                    // Unclosed, these aren't two inline code markers around a third either
+                    i += 2;
+                }
+            }
+            '`' => {
+                if let Some(end) = find_closing_marker(&chars, i) {
+                    replacements[i] = Some(MONOSPACE);
+                    replacements[end] = Some(MONOSPACE);
+                    i = end;
+                }
+            }
+            marker @ '*' | marker @ '_' | marker @ '~' if replacements[i].is_none() => {
+                let opens = (i == 0 || !chars[i - 1].is_alphanumeric())
+                    && chars.get(i + 1).is_some_and(|c| !c.is_whitespace());
+                match find_closing_marker(&chars, i) {
+                    Some(end) if opens && replacements[end].is_none() => {
+                        let code = match marker {
+                            '*' => BOLD,
+                            '_' => ITALIC,
+                            _ => STRIKETHROUGH,
+                        };
+                        replacements[i] = Some(code);
+                        replacements[end] = Some(code);
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    let mut result = String::new();
+    for (c, replacement) in chars.into_iter().zip(replacements) {
+        match replacement {
+            Some(replacement) => result += replacement,
+            None => result.push(c),
+        }
+    }
+    result
+}
+
+fn find_from(chars: &[char], start: usize, needle: char) -> Option<usize> {
+    chars[start..]
+        .iter()
+        .position(|&c| c == needle)
+        .map(|pos| start + pos)
+}
+
+fn find_code_block_end(chars: &[char], start: usize) -> Option<usize> {
+    (start..chars.len().saturating_sub(2)).find(|&i| chars[i..].starts_with(&['`', '`', '`']))
+}
+
+// Finds the marker closing the one at start, on the same line
+fn find_closing_marker(chars: &[char], start: usize) -> Option<usize> {
+    let marker = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() {
+        match chars[i] {
+            '\n' => return None,
+            '<' if marker != '`' => i = find_from(chars, i, '>')?,
+            c if c == marker => {
+                let closes = i > start + 1
+                    && !chars[i - 1].is_whitespace()
+                    && chars.get(i + 1).map_or(true, |c| !c.is_alphanumeric());
+                if closes || marker == '`' {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+    None
+}
+
+// Resolves <...> entities and decodes the HTML escapes Slack uses for &, < and >
+async fn replace_entities(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find('<') {
+        result += &decode_html_entities(&rest[..start]);
+        let entity = &rest[start + 1..];
+        match entity.find('>') {
+            Some(end) => {
+                result += &render_entity(&entity[..end]).await;
+                rest = &entity[end + 1..];
+            }
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        }
+    }
+    result + &decode_html_entities(rest)
+}
+
+async fn render_entity(entity: &str) -> String {
+    let (target, label) = match entity.find('|') {
+        Some(pos) => (
+            &entity[..pos],
+            Some(decode_html_entities(&entity[pos + 1..])),
+        ),
+        None => (entity, None),
+    };
+
+    if let Some(user_id) = target.strip_prefix('@') {
+        let username = get_username(user_id)
+            .or_else(|| label.map(|label| label.trim_start_matches('@').to_owned()))
+            .unwrap_or_else(|| user_id.to_owned());
+        "@".to_owned() + &username
+    } else if let Some(channel_id) = target.strip_prefix('#') {
+        match label {
+            Some(label) => "#".to_owned() + &label,
+            None => match get_irc_channel_name(channel_id).await {
+                Some(irc_chan_name) => irc_chan_name,
+                None => "#".to_owned() + channel_id,
+            },
+        }
+    } else if let Some(special) = target.strip_prefix('!') {
+        // Broadcasts like <!here>, or things like <!date^...|fallback> and <!subteam^...|@team>
+        match label {
+            Some(label) => label,
+            None => "@".to_owned() + special.split('^').next().unwrap_or(special),
+        }
+    } else {
+        let url = decode_html_entities(target);
+        let plain_url = url.strip_prefix("mailto:").unwrap_or(&url).to_owned();
+        match label {
+            Some(label) if label != url && label != plain_url => format!("{} ({})", label, url),
+            _ => plain_url,
+        }
+    }
+}
+
+fn decode_html_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+// IRC messages can't span multiple lines, this also drops the lines with nothing to show
+pub fn split_irc_lines(text: &str) -> Vec<String> {
+    text.lines()
+        .filter(|line| line.chars().any(|c| !c.is_whitespace() && !c.is_control()))
+        .map(|line| line.to_owned())
+        .collect()
+}
+
+// Wraps the text of a /me in a CTCP ACTION
+pub fn make_ctcp_action(text: &str) -> String {
+    "\x01ACTION ".to_owned() + text + "\x01"
+}
+
+// Returns the text of a CTCP ACTION, some clients leave out the final \x01
+pub fn parse_ctcp_action(text: &str) -> Option<&str> {
+    let action = text.strip_prefix("\x01ACTION ")?;
+    Some(action.strip_suffix('\x01').unwrap_or(action))
+}
+
+// Converts IRC formatting and mentions of nicks and bridged channels to Slack mrkdwn
+pub async fn irc_to_slack(team_id: &str, text: &str) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut result = String::new();
+    // Slack markers we opened and have yet to close, in order
+    let mut open_markers: Vec<char> = Vec::new();
+
+    // IRC users address each other with "nick: message"
+    let mut i = 0;
+    if let Some(pos) = chars.iter().position(|&c| c == ':' || c == ',') {
+        let nick = chars[..pos].iter().collect::<String>();
+        let followed_by_space = chars.get(pos + 1).map_or(true, |c| c.is_whitespace());
+        if followed_by_space {
+            if let Some(user_id) = get_user_id(&nick) {
+                result += &format!("<@{}>", user_id);
+                i = pos;
+            }
+        }
+    }
+
+    while i < chars.len() {
+        let c = chars[i];
+        let at_word_start = i == 0 || !chars[i - 1].is_alphanumeric();
+        match c {
+            '&' => result += "&amp;",
+            '<' => result += "&lt;",
+            '>' => result += "&gt;",
+            '\x02' | '\x1d' | '\x1e' | '\x11' => {
+                let marker = match c {
+                    '\x02' => '*',
+                    '\x1d' => '_',
+                    '\x1e' => '~',
+                    _ => '`',
+                };
+                match open_markers.iter().position(|&m| m == marker) {
+                    Some(pos) => {
+                        open_markers.remove(pos);
+                    }
+                    None => open_markers.push(marker),
+                }
+                result.push(marker);
+            }
+            RESET => {
+                while let Some(marker) = open_markers.pop() {
+                    result.push(marker);
+                }
+            }
+            UNDERLINE | REVERSE => (),
+            COLOR => i += count_color_chars(&chars[i + 1..], |c| c.is_ascii_digit(), 2),
+            HEX_COLOR => i += count_color_chars(&chars[i + 1..], |c| c.is_ascii_hexdigit(), 6),
+            '@' | '#' if at_word_start => {
+                let word_len = chars[i + 1..]
+                    .iter()
+                    .take_while(|c| !c.is_whitespace() && !matches!(c, ',' | ':' | '<' | '>'))
+                    .count();
+                match resolve_mention(team_id, c, &chars[i + 1..i + 1 + word_len]).await {
+                    Some((mention, mention_len)) => {
+                        result += &mention;
+                        i += mention_len;
+                    }
+                    None => result.push(c),
+                }
+            }
+            _ => result.push(c),
+        }
+        i += 1;
+    }
+
+    while let Some(marker) = open_markers.pop() {
+        result.push(marker);
+    }
+    result
+}
+
+// Counts the chars of a color code's parameters, like the "4,12" in "\x034,12"
+fn count_color_chars(chars: &[char], is_color_char: fn(&char) -> bool, max_len: usize) -> usize {
+    let fg_len = chars
+        .iter()
+        .take(max_len)
+        .take_while(|c| is_color_char(c))
+        .count();
+    if fg_len == 0 || chars.get(fg_len) != Some(&',') {
+        return fg_len;
+    }
+    let bg_len = chars[fg_len + 1..]
+        .iter()
+        .take(max_len)
+        .take_while(|c| is_color_char(c))
+        .count();
+    if bg_len == 0 {
+        fg_len
+    } else {
+        fg_len + 1 + bg_len
+    }
+}
+
+// Resolves "@nick" or "#channel", ignoring trailing punctuation. Returns the mention and its length
+async fn resolve_mention(team_id: &str, sigil: char, word: &[char]) -> Option<(String, usize)> {
+    let mut len = word.len();
+    while len > 0 {
+        let name = word[..len].iter().collect::<String>();
+        let mention = if sigil == '@' {
+            get_user_id(&name).map(|user_id| format!("<@{}>", user_id))
+        } else {
+            get_channel_id(team_id, &("#".to_owned() + &name))
+                .await
+                .map(|channel_id| format!("<#{}>", channel_id))
+        };
+        if mention.is_some() {
+            return mention.map(|mention| (mention, len));
+        }
+        if word[len - 1].is_alphanumeric() {
+            return None;
+        }
+        len -= 1;
+    }
+    None
+}
+
+// Slack names of the emoji IRC clients most often react with
+const UNICODE_REACTIONS: &[(&str, &str)] = &[
+    ("👍", "+1"),
+    ("👎", "-1"),
+    ("❤️", "heart"),
+    ("❤", "heart"),
+    ("😂", "joy"),
+    ("😄", "smile"),
+    ("🎉", "tada"),
+    ("👀", "eyes"),
+    ("🔥", "fire"),
+    ("🙏", "pray"),
+    ("✅", "white_check_mark"),
+    ("😢", "cry"),
+    ("😮", "open_mouth"),
+];
+
+// The Slack emoji name of a reaction from IRC, either ":name:" or one of the common emoji
+pub fn slack_reaction_name(reaction: &str) -> Option<String> {
+    if let Some(name) = reaction
+        .strip_prefix(':')
+        .and_then(|name| name.strip_suffix(':'))
+    {
+        return Some(name.to_owned()).filter(|name| !name.is_empty() && !name.contains(' '));
+    }
+    UNICODE_REACTIONS
+        .iter()
+        .find(|(emoji, _)| *emoji == reaction)
+        .map(|(_, name)| (*name).to_owned())
+}
+
+// Slack's "+:emoji:" shorthand reacts to the last message instead of posting it
+pub fn parse_reaction_shorthand(text: &str) -> Option<String> {
+    slack_reaction_name(text.trim().strip_prefix('+')?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mrkdwn_markers() {
+        let cases = [
+            ("*bold*", "\x02bold\x02"),
+            ("_it_ and ~st~", "\x1dit\x1d and \x1est\x1e"),
+            ("*bold _both_*", "\x02bold \x1dboth\x1d\x02"),
+            ("_*both*_", "\x1d\x02both\x02\x1d"),
+            ("snake_case_name", "snake_case_name"),
+            ("2 * 3 * 4", "2 * 3 * 4"),
+            ("*unclosed", "*unclosed"),
+            ("*not\nbold*", "*not\nbold*"),
+            ("<http://a_b_c.com|*x*>", "<http://a_b_c.com|*x*>"),
+            ("*<http://a.com|x>*", "\x02<http://a.com|x>\x02"),
+        ];
+        for (mrkdwn, irc) in &cases {
+            assert_eq!(apply_mrkdwn(mrkdwn), *irc, "{:?}", mrkdwn);
+        }
+    }
+
+    #[test]
+    fn mrkdwn_code() {
+        let cases = [
+            ("`code`", "\x11code\x11"),
+            ("`*not bold*`", "\x11*not bold*\x11"),
+            ("```\nline1\nline2```", "\x11\n\x11line1\n\x11line2"),
+            ("```*a* _b_```", "\x11*a* _b_"),
+            ("```unclosed", "```unclosed"),
+        ];
+        for (mrkdwn, irc) in &cases {
+            assert_eq!(apply_mrkdwn(mrkdwn), *irc, "{:?}", mrkdwn);
+        }
+    }
+
+    #[tokio::test]
+    async fn slack_entities() {
+        let cases = [
+            ("a &amp; b &lt;c&gt;", "a & b <c>"),
+            ("&amp;lt;", "&lt;"),
+            ("<https://example.com>", "https://example.com"),
+            (
+                "<https://example.com|Example>",
+                "Example (https://example.com)",
+            ),
+            ("<https://a.com?x=1&amp;y=2>", "https://a.com?x=1&y=2"),
+            ("<mailto:a@b.com|a@b.com>", "a@b.com"),
+            ("<!here>", "@here"),
+            ("<!subteam^S123|@team>", "@team"),
+            ("<@U0UNKNOWN|bob>", "@bob"),
+            ("<@U0UNKNOWN>", "@U0UNKNOWN"),
+            ("<#C0UNKNOWN|general>", "#general"),
+            ("<unterminated", "<unterminated"),
+            ("*<https://a.com|link>*", "\x02link (https://a.com)\x02"),
+        ];
+        for (slack, irc) in &cases {
+            assert_eq!(slack_to_irc(slack).await, *irc, "{:?}", slack);
+        }
+    }
+
+    #[tokio::test]
+    async fn irc_formatting() {
+        let cases = [
+            ("\x02bold\x02", "*bold*"),
+            ("\x02unclosed", "*unclosed*"),
+            ("\x02\x1dboth\x0f rest", "*_both_* rest"),
+            ("\x1dit\x1d \x1est\x1e \x11code\x11", "_it_ ~st~ `code`"),
+            ("\x1funder\x1f \x16rev\x16", "under rev"),
+            ("a < b & c > d", "a &lt; b &amp; c &gt; d"),
+        ];
+        for (irc, slack) in &cases {
+            assert_eq!(irc_to_slack("T0", irc).await, *slack, "{:?}", irc);
+        }
+    }
+
+    #[tokio::test]
+    async fn irc_color_codes() {
+        let cases = [
+            ("\x034red\x03 plain", "red plain"),
+            ("\x034,12red on blue", "red on blue"),
+            ("\x031234", "34"),
+            ("\x03,5x", ",5x"),
+            ("\x034,x", ",x"),
+            ("\x04ff0000red\x04", "red"),
+            ("\x04ff0000,00ff00both", "both"),
+        ];
+        for (irc, slack) in &cases {
+            assert_eq!(irc_to_slack("T0", irc).await, *slack, "{:?}", irc);
+        }
+    }
+
+    #[tokio::test]
+    async fn irc_unknown_mentions() {
+        let cases = [
+            ("@nobody hi", "@nobody hi"),
+            ("#unbridged", "#unbridged"),
+            ("nobody: hi", "nobody: hi"),
+            ("mail@example.com", "mail@example.com"),
+        ];
+        for (irc, slack) in &cases {
+            assert_eq!(irc_to_slack("T0", irc).await, *slack, "{:?}", irc);
+        }
+    }
+}
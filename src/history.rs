@@ -0,0 +1,367 @@
+use crate::channels::{get_channel_id, get_im_channel_id, is_im_channel_id};
+use crate::client::{Client, IRC_SERVER_NAME};
+use crate::messages::{
+    is_relayed_subtype, message_sender, parse_msgid, render_slack_message, server_time_to_ts,
+    ts_to_datetime,
+};
+use crate::settings::GLOBAL_SETTINGS;
+use crate::slack::{Message as SlackMessage, Slack};
+use crate::users::{get_user_id, get_username, irc_source};
+use rirc_server::{ClientSender as IRCClientSender, Message as IRCMsg};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Max number of messages we answer a single CHATHISTORY request with
+const MAX_CHATHISTORY_LIMIT: usize = 100;
+// Max number of threads we fetch the replies of for a single history request
+const MAX_THREAD_FETCHES: usize = 10;
+
+static NEXT_BATCH_ID: AtomicUsize = AtomicUsize::new(0);
+
+// An IRC client history gets replayed to, and the capabilities it negotiated
+pub struct HistoryTarget {
+    pub addr: SocketAddr,
+    pub team_id: String,
+    pub nick: String,
+    pub user_id: String,
+    pub irc_sender: IRCClientSender,
+    pub server_time: bool,
+    pub batch: bool,
+}
+
+impl HistoryTarget {
+    pub fn new(client: &Client) -> HistoryTarget {
+        HistoryTarget {
+            addr: client.addr,
+            team_id: client.team_id.clone(),
+            nick: client.nick.clone(),
+            user_id: client.user_id.clone(),
+            irc_sender: client.irc_sender.clone(),
+            server_time: client.server_time,
+            batch: client.batch,
+        }
+    }
+}
+
+// Replays the latest messages of the Slack channel to a client that just joined the IRC channel
+pub async fn backfill_channel(
+    slack: &Slack,
+    target: &HistoryTarget,
+    slack_channel_id: &str,
+    irc_chan_name: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let count = GLOBAL_SETTINGS.read().unwrap().history_backfill_count;
+    if count == 0 {
+        return Ok(());
+    }
+
+    let messages = fetch_messages(slack, slack_channel_id, None, None, false, count, true).await?;
+    replay_messages(target, slack_channel_id, irc_chan_name, messages).await
+}
+
+// Answers the LATEST, BEFORE, AFTER, AROUND and BETWEEN subcommands of CHATHISTORY
+pub async fn handle_chathistory(
+    slack: &Slack,
+    target: &HistoryTarget,
+    team_id: &str,
+    params: &[String],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let subcommand = params
+        .first()
+        .map(|subcommand| subcommand.to_ascii_uppercase())
+        .unwrap_or_default();
+    let (irc_target, references, limit) = match (subcommand.as_str(), params.len()) {
+        ("LATEST", 4) | ("BEFORE", 4) | ("AFTER", 4) | ("AROUND", 4) => {
+            (&params[1], &params[2..3], &params[3])
+        }
+        ("BETWEEN", 5) => (&params[1], &params[2..4], &params[4]),
+        _ => {
+            return send_fail(
+                target,
+                "INVALID_PARAMS",
+                &[&subcommand],
+                "Invalid parameters",
+            )
+            .await
+        }
+    };
+    let limit = match limit.parse::<usize>() {
+        Ok(limit) => limit.min(MAX_CHATHISTORY_LIMIT),
+        Err(_) => {
+            return send_fail(target, "INVALID_PARAMS", &[&subcommand], "Invalid limit").await
+        }
+    };
+
+    let slack_channel_id = if irc_target.starts_with('#') || irc_target.starts_with('&') {
+        get_channel_id(team_id, irc_target).await
+    } else {
+        match get_user_id(irc_target) {
+            Some(user_id) => get_im_channel_id(&target.addr, &user_id).await,
+            None => None,
+        }
+    };
+    let slack_channel_id = match slack_channel_id {
+        Some(slack_channel_id) => slack_channel_id,
+        None => {
+            return send_fail(
+                target,
+                "INVALID_TARGET",
+                &[&subcommand, irc_target],
+                "Unknown target",
+            )
+            .await
+        }
+    };
+
+    let mut ref_ts = Vec::new();
+    for reference in references {
+        if subcommand == "LATEST" && reference == "*" {
+            continue;
+        }
+        match parse_reference(&slack_channel_id, reference) {
+            Some(ts) => ref_ts.push(ts),
+            None => {
+                return send_fail(
+                    target,
+                    "INVALID_MSGREFTOKEN",
+                    &[&subcommand, reference],
+                    "Invalid message reference",
+                )
+                .await
+            }
+        }
+    }
+
+    let channel = slack_channel_id.as_str();
+    let messages = match (subcommand.as_str(), ref_ts.as_slice()) {
+        ("LATEST", []) => fetch_messages(slack, channel, None, None, false, limit, true).await?,
+        ("LATEST", [ts]) => {
+            fetch_messages(slack, channel, Some(ts), None, false, limit, true).await?
+        }
+        ("BEFORE", [ts]) => {
+            fetch_messages(slack, channel, None, Some(ts), false, limit, true).await?
+        }
+        ("AFTER", [ts]) => {
+            fetch_messages(slack, channel, Some(ts), None, false, limit, false).await?
+        }
+        ("AROUND", [ts]) => {
+            let before_limit = limit / 2;
+            let mut messages =
+                fetch_messages(slack, channel, None, Some(ts), false, before_limit, true).await?;
+            let after_limit = limit - before_limit;
+            messages.append(
+                &mut fetch_messages(slack, channel, Some(ts), None, true, after_limit, false)
+                    .await?,
+            );
+            messages
+        }
+        // The messages closest to the first reference, in either direction
+        ("BETWEEN", [start, end]) if start <= end => {
+            fetch_messages(slack, channel, Some(start), Some(end), false, limit, false).await?
+        }
+        ("BETWEEN", [start, end]) => {
+            fetch_messages(slack, channel, Some(end), Some(start), false, limit, true).await?
+        }
+        _ => unreachable!(),
+    };
+
+    replay_messages(target, channel, irc_target, messages).await
+}
+
+// CHATHISTORY references messages either with timestamp=<server-time> or msgid=<msgid>
+fn parse_reference(slack_channel_id: &str, reference: &str) -> Option<String> {
+    if let Some(time) = reference.strip_prefix("timestamp=") {
+        server_time_to_ts(time)
+    } else if let Some(msgid) = reference.strip_prefix("msgid=") {
+        match parse_msgid(msgid) {
+            Some((channel_id, ts)) if channel_id == slack_channel_id => Some(ts.to_owned()),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+// Messages between oldest and latest, thread replies included, sorted oldest first.
+// Only the `limit` messages closest to latest are kept if newest is set, or to oldest otherwise.
+async fn fetch_messages(
+    slack: &Slack,
+    slack_channel_id: &str,
+    oldest: Option<&str>,
+    latest: Option<&str>,
+    inclusive: bool,
+    limit: usize,
+    newest: bool,
+) -> Result<Vec<SlackMessage>, Box<dyn Error + Send + Sync>> {
+    let mut messages = slack
+        .conversations_history(slack_channel_id, oldest, latest, inclusive, limit)
+        .await?;
+
+    // conversations.history only has the thread replies that were also sent to the channel.
+    // Only fetch the threads closest to the end of the history we keep.
+    let mut thread_parents = messages
+        .iter()
+        .filter(|message| message.reply_count > 0)
+        .map(|message| message.ts.clone())
+        .collect::<Vec<_>>();
+    thread_parents.sort();
+    if thread_parents.len() > MAX_THREAD_FETCHES {
+        if newest {
+            thread_parents.drain(..thread_parents.len() - MAX_THREAD_FETCHES);
+        } else {
+            thread_parents.truncate(MAX_THREAD_FETCHES);
+        }
+    }
+    for thread_ts in thread_parents {
+        let replies = match slack
+            .conversations_replies(slack_channel_id, &thread_ts)
+            .await
+        {
+            Ok(replies) => replies,
+            Err(err) => {
+                println!(
+                    "Couldn't get replies of thread {} in channel {}: {}",
+                    thread_ts, slack_channel_id, err
+                );
+                continue;
+            }
+        };
+        for reply in replies {
+            let after_oldest = oldest.map_or(true, |oldest| {
+                reply.ts.as_str() > oldest || (inclusive && reply.ts == oldest)
+            });
+            let before_latest = latest.map_or(true, |latest| {
+                reply.ts.as_str() < latest || (inclusive && reply.ts == latest)
+            });
+            if after_oldest && before_latest && !messages.iter().any(|m| m.ts == reply.ts) {
+                messages.push(reply);
+            }
+        }
+    }
+
+    // Slack timestamps all have the same number of digits, so they sort as strings
+    messages.sort_by(|a, b| a.ts.cmp(&b.ts));
+    if messages.len() > limit {
+        if newest {
+            messages.drain(..messages.len() - limit);
+        } else {
+            messages.truncate(limit);
+        }
+    }
+    Ok(messages)
+}
+
+async fn send_fail(
+    target: &HistoryTarget,
+    code: &str,
+    context: &[&str],
+    description: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut params = vec!["CHATHISTORY".to_owned(), code.to_owned()];
+    params.extend(context.iter().map(|param| param.to_string()));
+    params.push(description.to_owned());
+    target
+        .irc_sender
+        .send(IRCMsg {
+            tags: Vec::new(),
+            source: Some(IRC_SERVER_NAME.to_owned()),
+            command: "FAIL".to_owned(),
+            params,
+        })
+        .await
+}
+
+// Sends the messages in order, in a chathistory batch if the client supports it.
+// The IRC target is the IRC channel, or the nick on the other side for IMs.
+async fn replay_messages(
+    target: &HistoryTarget,
+    slack_channel_id: &str,
+    irc_target: &str,
+    messages: Vec<SlackMessage>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let batch_id = if target.batch {
+        let batch_id = format!("{:x}", NEXT_BATCH_ID.fetch_add(1, Ordering::Relaxed));
+        target
+            .irc_sender
+            .send(IRCMsg {
+                tags: Vec::new(),
+                source: Some(IRC_SERVER_NAME.to_owned()),
+                command: "BATCH".to_owned(),
+                params: vec![
+                    "+".to_owned() + &batch_id,
+                    "chathistory".to_owned(),
+                    irc_target.to_owned(),
+                ],
+            })
+            .await?;
+        Some(batch_id)
+    } else {
+        None
+    };
+
+    let is_im = is_im_channel_id(slack_channel_id);
+    for message in messages {
+        // Joins, topic changes and the like were already shown when they happened
+        if !is_relayed_subtype(message.subtype.as_deref()) {
+            continue;
+        }
+        let user = match message_sender(&message) {
+            Some(user) => user,
+            None => continue,
+        };
+        // Like live IMs, our own messages show up as queries to the other side
+        let (source_nick, msg_target) = if !is_im {
+            (
+                get_username(&user).unwrap_or_else(|| user.to_owned()),
+                irc_target,
+            )
+        } else if user == target.user_id {
+            (target.nick.clone(), irc_target)
+        } else {
+            (irc_target.to_owned(), target.nick.as_str())
+        };
+
+        // Clients without server-time would show old messages as if they were sent just now
+        let timestamp_prefix = match ts_to_datetime(&message.ts) {
+            Some(time) if !target.server_time => time.format("[%Y-%m-%d %H:%M] ").to_string(),
+            _ => String::new(),
+        };
+        let lines = render_slack_message(
+            &target.team_id,
+            slack_channel_id,
+            &message,
+            &timestamp_prefix,
+        )
+        .await;
+
+        for (mut tags, line) in lines {
+            if let Some(batch_id) = &batch_id {
+                tags.push("batch=".to_owned() + batch_id);
+            }
+            target
+                .irc_sender
+                .send(IRCMsg {
+                    tags,
+                    source: Some(irc_source(&source_nick)),
+                    command: "PRIVMSG".to_owned(),
+                    params: vec![msg_target.to_owned(), line],
+                })
+                .await?;
+        }
+    }
+
+    if let Some(batch_id) = batch_id {
+        target
+            .irc_sender
+            .send(IRCMsg {
+                tags: Vec::new(),
+                source: Some(IRC_SERVER_NAME.to_owned()),
+                command: "BATCH".to_owned(),
+                params: vec!["-".to_owned() + &batch_id],
+            })
+            .await?;
+    }
+
+    Ok(())
+}
@@ -6,21 +6,32 @@ extern crate serde_json;
 extern crate serde_derive;
 #[macro_use]
 extern crate lazy_static;
+extern crate chrono;
 extern crate futures;
+extern crate hex;
+extern crate hmac;
 extern crate hyper;
+extern crate sha2;
+extern crate tokio_tungstenite;
 
 #[macro_use]
 mod server;
+mod blocks;
 mod channels;
 mod client;
+mod files;
+mod formatting;
+mod history;
+mod messages;
 mod settings;
 mod slack;
+mod uploads;
 mod users;
 
-use client::get_server_callbacks;
+use client::{get_server_callbacks, IRC_SERVER_NAME};
 use rirc_server::{Server, ServerSettings};
 use server::SlackAppServer;
-use settings::GLOBAL_SETTINGS;
+use settings::{SlackAppTransport, GLOBAL_SETTINGS};
 use std::error::Error;
 
 #[tokio::main]
@@ -33,7 +44,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let mut irc_server = Server::new(
             ServerSettings {
                 listen_addr: "0.0.0.0:6697".parse().unwrap(),
-                server_name: "rIRC-slack-gateway".to_owned(),
+                server_name: IRC_SERVER_NAME.to_owned(),
                 ..Default::default()
             },
             get_server_callbacks(),
@@ -42,12 +53,31 @@ async fn main() -> Result<(), Box<dyn Error>> {
         irc_server.start().await.unwrap();
     });
 
-    let slack_app_listen_addr = settings.slack_app_listen_addr.parse()?;
-    let slack_app_verif_token = settings.slack_app_verif_token.clone();
-    let slack_fut = tokio::spawn(SlackAppServer::start(
-        slack_app_listen_addr,
-        slack_app_verif_token,
-    ));
+    let slack_fut = match settings.slack_app_transport {
+        SlackAppTransport::Http => {
+            // Older configs had a verification token instead, which Slack deprecated
+            if settings.slack_app_signing_secret.is_empty() {
+                return Err(From::from(
+                    "The http transport needs slack_app_signing_secret, check the server config",
+                ));
+            }
+            let slack_app_listen_addr = settings.slack_app_listen_addr.parse()?;
+            let slack_app_signing_secret = settings.slack_app_signing_secret.clone();
+            tokio::spawn(SlackAppServer::start(
+                slack_app_listen_addr,
+                slack_app_signing_secret,
+            ))
+        }
+        SlackAppTransport::SocketMode => {
+            if settings.slack_app_token.is_empty() {
+                return Err(From::from(
+                    "The socket_mode transport needs slack_app_token, check the server config",
+                ));
+            }
+            let slack_app_token = settings.slack_app_token.clone();
+            tokio::spawn(SlackAppServer::start_socket_mode(slack_app_token))
+        }
+    };
 
     irc_fut.await.unwrap();
     slack_fut.await.unwrap();
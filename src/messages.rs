@@ -0,0 +1,298 @@
+use crate::blocks::{bot_nick, render_message_text};
+use crate::files::append_file_descriptions;
+use crate::formatting::{make_ctcp_action, split_irc_lines};
+use crate::slack::Message as SlackMessage;
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use tokio::sync::RwLock;
+
+// Max number of relayed Slack messages we remember per channel, older ones are forgotten
+const MAX_RELAYED_MSGS_PER_CHANNEL: usize = 256;
+// Max number of messages posted from IRC that each client can still delete, per channel
+const MAX_POSTED_MSGS_PER_CHANNEL: usize = 16;
+
+// Thread replies are prefixed with these on IRC, and IRC users reply in threads the same way
+const THREAD_PREFIX: &str = "[thread ";
+const THREAD_BROADCAST_PREFIX: &str = "[thread! ";
+
+// Subtypes of the Slack messages relayed as IRC messages, the others are events of their own
+const RELAYED_SUBTYPES: &[&str] = &[
+    "thread_broadcast",
+    "file_share",
+    "me_message",
+    "bot_message",
+];
+
+// An IRC line and its IRCv3 tags
+pub type TaggedLine = (Vec<String>, String);
+
+#[derive(Clone)]
+pub struct RelayedMessage {
+    pub ts: String,
+    pub thread_ts: Option<String>,
+    pub text: String,
+}
+
+lazy_static! {
+    // Slack channel IDs to the messages recently relayed from them, oldest first
+    static ref GLOBAL_RELAYED_MSGS: RwLock<HashMap<String, VecDeque<RelayedMessage>>> =
+        RwLock::new(HashMap::new());
+    // IRC clients to Slack channel IDs to the ts of the messages they posted, oldest first
+    static ref GLOBAL_POSTED_MSGS: RwLock<HashMap<SocketAddr, HashMap<String, Vec<String>>>> =
+        RwLock::new(HashMap::new());
+}
+
+pub fn is_relayed_subtype(subtype: Option<&str>) -> bool {
+    match subtype {
+        Some(subtype) => RELAYED_SUBTYPES.contains(&subtype),
+        None => true,
+    }
+}
+
+// The Slack user ID a relayed message is from, or the nick of the bot or app that posted it
+pub fn message_sender(message: &SlackMessage) -> Option<String> {
+    let is_bot = message.subtype.as_deref() == Some("bot_message") || message.user.is_none();
+    if is_bot && message.bot_id.is_some() {
+        return Some(bot_nick(
+            message.username.as_deref(),
+            message
+                .bot_profile
+                .as_ref()
+                .and_then(|bot| bot.name.as_deref()),
+            message.bot_id.as_deref(),
+        ));
+    }
+    message.user.clone()
+}
+
+// Renders a Slack message as tagged IRC lines, and remembers it for threads, edits and reactions.
+// The prefix goes in front of each line, inside of /me actions.
+pub async fn render_slack_message(
+    team_id: &str,
+    slack_channel_id: &str,
+    message: &SlackMessage,
+    line_prefix: &str,
+) -> Vec<TaggedLine> {
+    let text = render_message_text(&message.text, &message.blocks, &message.attachments).await;
+    let text = append_file_descriptions(team_id, text, &message.files);
+    let thread_ts = message.thread_ts.as_deref();
+    remember_message(slack_channel_id, &message.ts, thread_ts, &text).await;
+
+    let reply_ts = thread_ts.filter(|thread_ts| *thread_ts != message.ts);
+    let text = match reply_ts {
+        Some(thread_ts) => format_thread_prefix(thread_ts) + &text,
+        None => text,
+    };
+    let is_action = message.subtype.as_deref() == Some("me_message");
+    make_tagged_lines(slack_channel_id, &message.ts, reply_ts, &text)
+        .into_iter()
+        .map(|(tags, line)| {
+            let line = line_prefix.to_owned() + &line;
+            if is_action {
+                (tags, make_ctcp_action(&line))
+            } else {
+                (tags, line)
+            }
+        })
+        .collect()
+}
+
+pub fn make_msgid(slack_channel_id: &str, ts: &str) -> String {
+    slack_channel_id.to_owned() + "-" + ts
+}
+
+// Splits a msgid back into the Slack channel ID and the message ts
+pub fn parse_msgid(msgid: &str) -> Option<(&str, &str)> {
+    let (slack_channel_id, ts) = msgid.split_once('-')?;
+    // The extra IRC lines of multi-line messages have a ":<line>" suffix
+    Some((slack_channel_id, ts.split(':').next().unwrap()))
+}
+
+// The IRCv3 tags of a relayed Slack message: its time, its msgid, and the msgid of the message
+// it replies to. The IRC server only sends clients the tags of the capabilities they enabled.
+pub fn make_message_tags(slack_channel_id: &str, ts: &str, reply_ts: Option<&str>) -> Vec<String> {
+    let mut tags = Vec::new();
+    if let Some(time) = format_server_time(ts) {
+        tags.push("time=".to_owned() + &time);
+    }
+    tags.push("msgid=".to_owned() + &make_msgid(slack_channel_id, ts));
+    if let Some(reply_ts) = reply_ts {
+        tags.push("+draft/reply=".to_owned() + &make_msgid(slack_channel_id, reply_ts));
+    }
+    tags
+}
+
+// Splits a relayed Slack message into IRC lines, each with the tags of the message
+pub fn make_tagged_lines(
+    slack_channel_id: &str,
+    ts: &str,
+    reply_ts: Option<&str>,
+    text: &str,
+) -> Vec<TaggedLine> {
+    let tags = make_message_tags(slack_channel_id, ts, reply_ts);
+
+    split_irc_lines(text)
+        .into_iter()
+        .enumerate()
+        .map(|(line_num, line)| {
+            // Clients would drop the other lines as duplicates if they had the same msgid
+            let line_tags = tags
+                .iter()
+                .map(|tag| {
+                    if line_num > 0 && tag.starts_with("msgid=") {
+                        format!("{}:{}", tag, line_num)
+                    } else {
+                        tag.clone()
+                    }
+                })
+                .collect();
+            (line_tags, line)
+        })
+        .collect()
+}
+
+// Slack timestamps are seconds since the epoch, with microseconds after the dot
+pub fn ts_to_datetime(ts: &str) -> Option<DateTime<Utc>> {
+    let (secs, micros) = ts.split_once('.').unwrap_or((ts, "0"));
+    Utc.timestamp_opt(secs.parse().ok()?, micros.parse::<u32>().ok()? * 1000)
+        .single()
+}
+
+// The Slack timestamp of an IRCv3 server-time
+pub fn server_time_to_ts(time: &str) -> Option<String> {
+    let time = DateTime::parse_from_rfc3339(time).ok()?;
+    Some(format!(
+        "{}.{:06}",
+        time.timestamp(),
+        time.timestamp_subsec_micros()
+    ))
+}
+
+// The value of the IRCv3 server-time tag for a Slack timestamp
+pub fn format_server_time(ts: &str) -> Option<String> {
+    ts_to_datetime(ts).map(|time| time.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())
+}
+
+pub async fn remember_message(
+    slack_channel_id: &str,
+    ts: &str,
+    thread_ts: Option<&str>,
+    text: &str,
+) {
+    let mut msgs_guard = GLOBAL_RELAYED_MSGS.write().await;
+    let channel_msgs = msgs_guard
+        .entry(slack_channel_id.to_owned())
+        .or_insert_with(VecDeque::new);
+    channel_msgs.retain(|msg| msg.ts != ts);
+    // Keep the messages in ts order, history replays remember older messages after newer ones
+    let index = channel_msgs
+        .iter()
+        .rposition(|msg| msg.ts.as_str() < ts)
+        .map_or(0, |index| index + 1);
+    channel_msgs.insert(
+        index,
+        RelayedMessage {
+            ts: ts.to_owned(),
+            thread_ts: thread_ts.map(|thread_ts| thread_ts.to_owned()),
+            text: text.to_owned(),
+        },
+    );
+
+    if channel_msgs.len() > MAX_RELAYED_MSGS_PER_CHANNEL {
+        channel_msgs.pop_front();
+    }
+}
+
+pub async fn get_message(slack_channel_id: &str, ts: &str) -> Option<RelayedMessage> {
+    let msgs_guard = GLOBAL_RELAYED_MSGS.read().await;
+    msgs_guard
+        .get(slack_channel_id)
+        .and_then(|channel_msgs| channel_msgs.iter().find(|msg| msg.ts == ts))
+        .cloned()
+}
+
+pub async fn get_last_message(slack_channel_id: &str) -> Option<RelayedMessage> {
+    let msgs_guard = GLOBAL_RELAYED_MSGS.read().await;
+    msgs_guard
+        .get(slack_channel_id)
+        .and_then(|channel_msgs| channel_msgs.back())
+        .cloned()
+}
+
+// Short reference to a thread, so IRC users can tell threads apart and reply to them
+pub fn thread_ref(thread_ts: &str) -> String {
+    // 32 bit FNV-1a, truncated to 24 bits
+    let hash = thread_ts.bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    });
+    format!("{:06x}", hash & 0x00ff_ffff)
+}
+
+pub fn format_thread_prefix(thread_ts: &str) -> String {
+    THREAD_PREFIX.to_owned() + &thread_ref(thread_ts) + "] "
+}
+
+// Splits "[thread <ref>] text" into the thread ref, whether to broadcast, and the text
+pub fn parse_thread_prefix(text: &str) -> Option<(&str, bool, &str)> {
+    let (reply_broadcast, rest) = match text.strip_prefix(THREAD_BROADCAST_PREFIX) {
+        Some(rest) => (true, rest),
+        None => (false, text.strip_prefix(THREAD_PREFIX)?),
+    };
+    let ref_end = rest.find(']')?;
+    Some((
+        &rest[..ref_end],
+        reply_broadcast,
+        rest[ref_end + 1..].trim_start(),
+    ))
+}
+
+// Finds the ts of the root of a recently active thread
+pub async fn find_thread_ts(slack_channel_id: &str, thread_ref_str: &str) -> Option<String> {
+    let msgs_guard = GLOBAL_RELAYED_MSGS.read().await;
+    msgs_guard
+        .get(slack_channel_id)?
+        .iter()
+        .rev()
+        .map(|msg| msg.thread_ts.as_ref().unwrap_or(&msg.ts))
+        .find(|root_ts| thread_ref(root_ts) == thread_ref_str)
+        .cloned()
+}
+
+pub async fn forget_message(slack_channel_id: &str, ts: &str) -> Option<RelayedMessage> {
+    let mut msgs_guard = GLOBAL_RELAYED_MSGS.write().await;
+    let channel_msgs = msgs_guard.get_mut(slack_channel_id)?;
+    let pos = channel_msgs.iter().position(|msg| msg.ts == ts)?;
+    channel_msgs.remove(pos)
+}
+
+pub async fn remember_posted_message(client_addr: SocketAddr, slack_channel_id: &str, ts: String) {
+    let mut posted_guard = GLOBAL_POSTED_MSGS.write().await;
+    let channel_msgs = posted_guard
+        .entry(client_addr)
+        .or_insert_with(HashMap::new)
+        .entry(slack_channel_id.to_owned())
+        .or_insert_with(Vec::new);
+    channel_msgs.push(ts);
+
+    if channel_msgs.len() > MAX_POSTED_MSGS_PER_CHANNEL {
+        channel_msgs.remove(0);
+    }
+}
+
+// Returns the ts of the last message this client posted in the channel, and forgets it
+pub async fn pop_posted_message(
+    client_addr: &SocketAddr,
+    slack_channel_id: &str,
+) -> Option<String> {
+    let mut posted_guard = GLOBAL_POSTED_MSGS.write().await;
+    posted_guard
+        .get_mut(client_addr)
+        .and_then(|client_msgs| client_msgs.get_mut(slack_channel_id))
+        .and_then(|channel_msgs| channel_msgs.pop())
+}
+
+pub async fn forget_posted_messages(client_addr: &SocketAddr) {
+    let mut posted_guard = GLOBAL_POSTED_MSGS.write().await;
+    posted_guard.remove(client_addr);
+}
@@ -1,9 +1,17 @@
+use crate::files::FILE_DOWNLOAD_PATH;
+use hmac::{Hmac, Mac, NewMac};
+use hyper::header::HeaderMap;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use serde_json::{self, Value};
+use sha2::Sha256;
 use std::convert::Infallible;
 use std::error::Error;
 use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Requests whose timestamp is further than this from our clock are rejected, to prevent replays
+const MAX_REQUEST_AGE_SECS: u64 = 60 * 5;
 
 macro_rules! return_error {
     ( $status_code:expr, $error:expr ) => {{
@@ -16,22 +24,24 @@ macro_rules! return_error {
     }};
 }
 
+mod downloads;
 mod handlers;
+mod socket_mode;
 
 pub(super) struct SlackAppServerState {
-    verif_token: String,
+    signing_secret: String,
 }
 
 pub struct SlackAppServer;
 
 impl SlackAppServer {
-    pub async fn start(listen_addr: SocketAddr, verif_token: String) {
-        if verif_token.is_empty() {
-            panic!("Slack app verification token must not be empty, check the server config");
+    pub async fn start(listen_addr: SocketAddr, signing_secret: String) {
+        if signing_secret.is_empty() {
+            panic!("Slack app signing secret must not be empty, check the server config");
         }
 
         let state: &'static SlackAppServerState =
-            Box::leak(Box::new(SlackAppServerState { verif_token }));
+            Box::leak(Box::new(SlackAppServerState { signing_secret }));
 
         let service = make_service_fn(|_| async move {
             let service_handler = move |req| Self::slack_service(state, req);
@@ -46,24 +56,25 @@ impl SlackAppServer {
         state: &SlackAppServerState,
         req: Request<Body>,
     ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
-        let payload = hyper::body::to_bytes(req.into_body()).await?;
+        let (parts, body) = req.into_parts();
+        if parts.method == Method::GET && parts.uri.path().starts_with(FILE_DOWNLOAD_PATH) {
+            return Self::handle_file_download(parts.uri.path()).await;
+        }
 
-        let json: Value = match serde_json::from_slice(payload.as_ref()) {
-            Ok(json) => json,
-            _ => return_error!(StatusCode::BAD_REQUEST, "Invalid JSON in request"),
-        };
+        let payload = hyper::body::to_bytes(body).await?;
 
-        let maybe_token = json.get("token");
-        if maybe_token.is_none()
-            || !maybe_token.unwrap().is_string()
-            || maybe_token.unwrap().as_str().unwrap() != state.verif_token
-        {
+        if !Self::verify_signature(state, &parts.headers, payload.as_ref()) {
             return_error!(
                 StatusCode::FORBIDDEN,
-                "Invalid or missing verification token"
+                "Invalid or missing request signature"
             );
         }
 
+        let json: Value = match serde_json::from_slice(payload.as_ref()) {
+            Ok(json) => json,
+            _ => return_error!(StatusCode::BAD_REQUEST, "Invalid JSON in request"),
+        };
+
         let event_type = match json.get("type") {
             Some(v) if v.is_string() => v.as_str().unwrap(),
             _ => return_error!(
@@ -79,6 +90,45 @@ impl SlackAppServer {
         }
     }
 
+    // See https://api.slack.com/authentication/verifying-requests-from-slack
+    fn verify_signature(state: &SlackAppServerState, headers: &HeaderMap, payload: &[u8]) -> bool {
+        let timestamp = match headers
+            .get("X-Slack-Request-Timestamp")
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(timestamp) => timestamp,
+            None => return false,
+        };
+        let request_time = match timestamp.parse::<u64>() {
+            Ok(request_time) => request_time,
+            Err(_) => return false,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if now.abs_diff(request_time) > MAX_REQUEST_AGE_SECS {
+            return false;
+        }
+
+        let signature = match headers
+            .get("X-Slack-Signature")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("v0="))
+            .and_then(|v| hex::decode(v).ok())
+        {
+            Some(signature) => signature,
+            None => return false,
+        };
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(state.signing_secret.as_bytes()).unwrap();
+        mac.update(b"v0:");
+        mac.update(timestamp.as_bytes());
+        mac.update(b":");
+        mac.update(payload);
+        mac.verify(&signature).is_ok()
+    }
+
     async fn slack_service(
         state: &SlackAppServerState,
         req: Request<Body>,
@@ -92,3 +142,99 @@ impl SlackAppServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    const SECRET: &str = "8f742231b10e8888abcd99yyyzzz85a5";
+    const PAYLOAD: &[u8] = br#"{"type":"event_callback","team_id":"T0123"}"#;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(timestamp: &str, payload: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("v0:{}:", timestamp).as_bytes());
+        mac.update(payload);
+        "v0=".to_owned() + &hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn headers(timestamp: &str, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Slack-Request-Timestamp",
+            HeaderValue::from_str(timestamp).unwrap(),
+        );
+        headers.insert(
+            "X-Slack-Signature",
+            HeaderValue::from_str(signature).unwrap(),
+        );
+        headers
+    }
+
+    fn verify(headers: &HeaderMap, payload: &[u8]) -> bool {
+        let state = SlackAppServerState {
+            signing_secret: SECRET.to_owned(),
+        };
+        SlackAppServer::verify_signature(&state, headers, payload)
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let timestamp = now().to_string();
+        let headers = headers(&timestamp, &sign(&timestamp, PAYLOAD));
+        assert!(verify(&headers, PAYLOAD));
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let timestamp = now().to_string();
+        let headers = headers(&timestamp, &sign(&timestamp, PAYLOAD));
+        assert!(!verify(
+            &headers,
+            br#"{"type":"event_callback","team_id":"T9999"}"#
+        ));
+    }
+
+    #[test]
+    fn rejects_stale_timestamp() {
+        for timestamp in &[
+            now() - MAX_REQUEST_AGE_SECS - 1,
+            now() + MAX_REQUEST_AGE_SECS + 1,
+        ] {
+            let timestamp = timestamp.to_string();
+            let headers = headers(&timestamp, &sign(&timestamp, PAYLOAD));
+            assert!(!verify(&headers, PAYLOAD));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let timestamp = now().to_string();
+        let signature = sign(&timestamp, PAYLOAD);
+        let cases = [
+            headers("not a number", &signature),
+            headers(&timestamp, signature.trim_start_matches("v0=")),
+            headers(&timestamp, &signature.replace("v0=", "v1=")),
+            headers(&timestamp, "v0=not-hex"),
+            headers(&timestamp, "v0="),
+            HeaderMap::new(),
+        ];
+        for headers in &cases {
+            assert!(!verify(headers, PAYLOAD));
+        }
+
+        let mut missing_signature = HeaderMap::new();
+        missing_signature.insert(
+            "X-Slack-Request-Timestamp",
+            HeaderValue::from_str(&timestamp).unwrap(),
+        );
+        assert!(!verify(&missing_signature, PAYLOAD));
+    }
+}
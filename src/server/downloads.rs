@@ -0,0 +1,59 @@
+use super::SlackAppServer;
+use crate::client::GLOBAL_CLIENTS;
+use crate::files::{get_shared_file, verify_file_link_signature, FILE_DOWNLOAD_PATH};
+use hyper::{Body, Response, StatusCode};
+use std::error::Error;
+
+impl SlackAppServer {
+    // Serves the files shared in Slack to IRC users, who don't have a Slack session to open them
+    pub(super) async fn handle_file_download(
+        path: &str,
+    ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        // The path is /files/<file ID>/<signature>/<file name>
+        let mut parts = path.trim_start_matches(FILE_DOWNLOAD_PATH).split('/');
+        let (file_id, signature) = match (parts.next(), parts.next()) {
+            (Some(file_id), Some(signature)) => (file_id, signature),
+            _ => return_error!(StatusCode::NOT_FOUND, "Invalid file link"),
+        };
+        if !verify_file_link_signature(file_id, signature) {
+            return_error!(StatusCode::FORBIDDEN, "Invalid file link signature");
+        }
+
+        let shared_file = match get_shared_file(file_id) {
+            Some(shared_file) => shared_file,
+            None => return_error!(StatusCode::NOT_FOUND, "Unknown or expired file link"),
+        };
+        let slack = {
+            let clients = GLOBAL_CLIENTS.read().unwrap();
+            match clients
+                .values()
+                .find(|client| client.team_id == shared_file.team_id)
+            {
+                Some(client) => client.slack.clone(),
+                None => return_error!(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Nobody from this Slack workspace is connected to download the file"
+                ),
+            }
+        };
+
+        println!("Downloading file {} for an IRC user", file_id);
+        let download = slack.download_file(&shared_file.download_url).await?;
+
+        // Files can be huge, so they're streamed through rather than held in memory. They're
+        // never shown inline either, HTML or SVG files must not run from the gateway's origin.
+        let mimetype = shared_file
+            .mimetype
+            .unwrap_or_else(|| "application/octet-stream".to_owned());
+        let mut response = Response::builder()
+            .header("Content-Type", mimetype)
+            .header("Content-Disposition", "attachment")
+            .header("X-Content-Type-Options", "nosniff");
+        if let Some(content_length) = download.content_length() {
+            response = response.header("Content-Length", content_length);
+        }
+        Ok(response
+            .body(Body::wrap_stream(download.bytes_stream()))
+            .unwrap())
+    }
+}
@@ -1,6 +1,19 @@
 use super::{SlackAppServerState, SlackAppServer};
-use crate::channels::{ack_message_from_irc, get_irc_channel};
-use crate::users::get_username;
+use crate::blocks::render_message_text;
+use crate::channels::{
+    ack_message_from_irc, get_im_route, get_irc_channel, get_irc_channels, is_im_channel_id,
+    join_virtual_user, part_virtual_user, set_channel_topic, set_virtual_user_away,
+};
+use crate::client::{
+    bridge_joined_channel, is_connected_user, refresh_ims, unbridge_left_channel, GLOBAL_CLIENTS,
+};
+use crate::formatting::{make_ctcp_action, slack_to_irc};
+use crate::messages::{
+    forget_message, format_thread_prefix, get_message, is_relayed_subtype, make_message_tags,
+    make_tagged_lines, message_sender, remember_message, render_slack_message, TaggedLine,
+};
+use crate::slack::{Message as SlackMessage, UserInfo};
+use crate::users::{get_away_message, get_user_info, get_username, irc_source, register_user};
 use hyper::{Body, Response, StatusCode};
 use rirc_server::Message;
 use serde_json::{Map, Value};
@@ -44,8 +57,29 @@ impl SlackAppServer {
             ),
         };
 
+        let team_id = match json.get("team_id") {
+            Some(v) if v.is_string() => v.as_str().unwrap(),
+            _ => return_error!(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid team_id field in request"
+            ),
+        };
+
         match event_type {
-            "message" => Self::handle_message_event_callback(state, event_object).await,
+            "message" => Self::handle_message_event_callback(state, team_id, event_object).await,
+            "member_joined_channel" => {
+                Self::handle_member_event_callback(team_id, event_object, true).await
+            }
+            "member_left_channel" => {
+                Self::handle_member_event_callback(team_id, event_object, false).await
+            }
+            "reaction_added" => {
+                Self::handle_reaction_event_callback(team_id, event_object, true).await
+            }
+            "reaction_removed" => {
+                Self::handle_reaction_event_callback(team_id, event_object, false).await
+            }
+            "user_change" => Self::handle_user_change_event_callback(team_id, event_object).await,
             _ => {
                 println!("Received unhandled event callback: {}", json);
                 Ok(Response::new("".into()))
@@ -55,14 +89,34 @@ impl SlackAppServer {
 
     pub(super) async fn handle_message_event_callback(
         _state: &SlackAppServerState,
+        team_id: &str,
         event_object: &Map<String, Value>,
     ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
-        if event_object.contains_key("subtype") {
-            println!(
-                "Received unhandled message event with subtype: {:?}",
-                event_object
-            );
-            return Ok(Response::new("".into()));
+        match event_object.get("subtype").and_then(|v| v.as_str()) {
+            subtype if is_relayed_subtype(subtype) => (),
+            Some("message_changed") => {
+                return Self::handle_message_changed_event_callback(team_id, event_object).await
+            }
+            Some("message_deleted") => {
+                return Self::handle_message_deleted_event_callback(team_id, event_object).await
+            }
+            // Slack also sends member_joined_channel and member_left_channel for these
+            Some("channel_join") | Some("group_join") => {
+                return Self::handle_member_event_callback(team_id, event_object, true).await
+            }
+            Some("channel_leave") | Some("group_leave") => {
+                return Self::handle_member_event_callback(team_id, event_object, false).await
+            }
+            Some("channel_topic") | Some("group_topic") => {
+                return Self::handle_topic_event_callback(team_id, event_object).await
+            }
+            _ => {
+                println!(
+                    "Received unhandled message event with subtype: {:?}",
+                    event_object
+                );
+                return Ok(Response::new("".into()));
+            }
         }
 
         let channel = match event_object.get("channel") {
@@ -73,60 +127,509 @@ impl SlackAppServer {
             ),
         };
 
-        let user = match event_object.get("user") {
+        let message: SlackMessage =
+            match serde_json::from_value(Value::Object(event_object.clone())) {
+                Ok(message) => message,
+                _ => return_error!(
+                    StatusCode::BAD_REQUEST,
+                    "Missing or invalid fields in message event"
+                ),
+            };
+        let ts = message.ts.as_str();
+
+        // Bots and apps post under a name of their own rather than as a Slack user
+        let user = match message_sender(&message) {
+            Some(user) => user,
+            None => return_error!(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid user field in message event"
+            ),
+        };
+
+        let from_irc = ack_message_from_irc(&channel, ts).await;
+        if from_irc && !is_im_channel_id(channel) {
+            // If the message comes from IRC, whoever's connected to the server already received it!
+            return Ok(Response::new("".into()));
+        }
+
+        println!(
+            "Received message '{}' ts {} from {} in channel {}",
+            message.text, ts, user, channel
+        );
+
+        let lines = render_slack_message(team_id, channel, &message, "").await;
+        Self::relay_to_irc(team_id, channel, &user, "PRIVMSG", lines, from_irc).await;
+
+        Ok(Response::new("".into()))
+    }
+
+    async fn handle_message_changed_event_callback(
+        team_id: &str,
+        event_object: &Map<String, Value>,
+    ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        let channel = match event_object.get("channel") {
             Some(v) if v.is_string() => v.as_str().unwrap(),
             _ => return_error!(
                 StatusCode::BAD_REQUEST,
-                "Missing or invalid user field in message event"
+                "Missing or invalid channel field in message_changed event"
+            ),
+        };
+
+        let message: SlackMessage = match event_object.get("message") {
+            Some(v) if v.is_object() => match serde_json::from_value(v.clone()) {
+                Ok(message) => message,
+                _ => return_error!(
+                    StatusCode::BAD_REQUEST,
+                    "Missing or invalid ts field in message_changed event"
+                ),
+            },
+            _ => return_error!(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid message field in message_changed event"
             ),
         };
+        let (text, ts) = (message.text.as_str(), message.ts.as_str());
+        let user = match message_sender(&message) {
+            Some(user) => user,
+            None => {
+                println!("Received edit of message ts {} without a sender", ts);
+                return Ok(Response::new("".into()));
+            }
+        };
+
+        // Slack also sends message_changed when unfurling links, that's not an edit
+        let previous_text = event_object
+            .get("previous_message")
+            .and_then(|v| v.get("text"))
+            .and_then(|v| v.as_str());
+        if previous_text == Some(text) {
+            return Ok(Response::new("".into()));
+        }
+
+        println!(
+            "Received edit '{}' of message ts {} from {} in channel {}",
+            text, ts, user, channel
+        );
+
+        let text = render_message_text(text, &message.blocks, &message.attachments).await;
+        let correction = match get_message(channel, ts).await {
+            Some(original) => format!("* edited \"{}\": {}", Self::excerpt(&original.text), text),
+            None => format!("* edited: {}", text),
+        };
+        let thread_ts = message.thread_ts.as_deref();
+        remember_message(channel, ts, thread_ts, &text).await;
+
+        let correction = match thread_ts {
+            Some(thread_ts) if thread_ts != ts => format_thread_prefix(thread_ts) + &correction,
+            _ => correction,
+        };
+        // The correction is a new IRC message, replying to the one it edits
+        let edit_ts = event_object
+            .get("ts")
+            .and_then(|v| v.as_str())
+            .unwrap_or(ts);
+        let lines = make_tagged_lines(channel, edit_ts, Some(ts), &correction);
+        Self::relay_to_irc(team_id, channel, &user, "PRIVMSG", lines, false).await;
+
+        Ok(Response::new("".into()))
+    }
 
-        let text = match event_object.get("text") {
+    async fn handle_message_deleted_event_callback(
+        team_id: &str,
+        event_object: &Map<String, Value>,
+    ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        let channel = match event_object.get("channel") {
             Some(v) if v.is_string() => v.as_str().unwrap(),
             _ => return_error!(
                 StatusCode::BAD_REQUEST,
-                "Missing or invalid text field in message event"
+                "Missing or invalid channel field in message_deleted event"
             ),
         };
 
-        let ts = match event_object.get("ts") {
+        let deleted_ts = match event_object.get("deleted_ts") {
             Some(v) if v.is_string() => v.as_str().unwrap(),
             _ => return_error!(
                 StatusCode::BAD_REQUEST,
-                "Missing or invalid ts field in message event"
+                "Missing or invalid deleted_ts field in message_deleted event"
             ),
         };
 
-        if ack_message_from_irc(&channel, ts).await {
-            // If the message comes from IRC, whoever's connected to the server already received it!
+        let previous_message = event_object.get("previous_message");
+        let user = match previous_message
+            .and_then(|v| serde_json::from_value::<SlackMessage>(v.clone()).ok())
+            .and_then(|message| message_sender(&message))
+        {
+            Some(user) => user,
+            None => {
+                println!(
+                    "Received deletion of message ts {} without a sender",
+                    deleted_ts
+                );
+                return Ok(Response::new("".into()));
+            }
+        };
+
+        println!(
+            "Received deletion of message ts {} from {} in channel {}",
+            deleted_ts, user, channel
+        );
+
+        let original_text = match forget_message(channel, deleted_ts).await {
+            Some(original) => Some(original.text),
+            None => match previous_message
+                .and_then(|v| v.get("text"))
+                .and_then(|v| v.as_str())
+            {
+                Some(text) => Some(slack_to_irc(text).await),
+                None => None,
+            },
+        };
+        let notice = match original_text {
+            Some(text) => format!("deleted a message: \"{}\"", Self::excerpt(&text)),
+            None => "deleted a message".to_owned(),
+        };
+        let deletion_ts = event_object
+            .get("ts")
+            .and_then(|v| v.as_str())
+            .unwrap_or(deleted_ts);
+        let lines = make_tagged_lines(channel, deletion_ts, Some(deleted_ts), &notice);
+        Self::relay_to_irc(team_id, channel, &user, "NOTICE", lines, false).await;
+
+        Ok(Response::new("".into()))
+    }
+
+    async fn handle_member_event_callback(
+        team_id: &str,
+        event_object: &Map<String, Value>,
+        joined: bool,
+    ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        let (channel, user) = match (event_object.get("channel"), event_object.get("user")) {
+            (Some(Value::String(channel)), Some(Value::String(user))) => (channel, user),
+            _ => return_error!(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid channel or user field in member event"
+            ),
+        };
+
+        // Users connected to the gateway join and leave as real IRC clients
+        if is_connected_user(user) {
+            if joined {
+                bridge_joined_channel(team_id, user, channel).await;
+            } else {
+                unbridge_left_channel(team_id, user, channel).await;
+            }
             return Ok(Response::new("".into()));
         }
 
+        let irc_chan = match get_irc_channel(team_id, channel).await {
+            Some(irc_chan) => irc_chan,
+            None => return Ok(Response::new("".into())),
+        };
+        let user_info = match get_user_info(user) {
+            Some(user_info) => user_info,
+            None => {
+                println!("Unknown user {} joined or left channel {}", user, channel);
+                return Ok(Response::new("".into()));
+            }
+        };
+
+        let mut irc_chan_guard = irc_chan.write().await;
+        if joined {
+            join_virtual_user(&mut irc_chan_guard, &user_info).await;
+        } else {
+            part_virtual_user(&mut irc_chan_guard, &user_info.name).await;
+        }
+
+        Ok(Response::new("".into()))
+    }
+
+    async fn handle_topic_event_callback(
+        team_id: &str,
+        event_object: &Map<String, Value>,
+    ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        let (channel, user, topic) = match (
+            event_object.get("channel"),
+            event_object.get("user"),
+            event_object.get("topic"),
+        ) {
+            (
+                Some(Value::String(channel)),
+                Some(Value::String(user)),
+                Some(Value::String(topic)),
+            ) => (channel, user, topic),
+            _ => return_error!(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid channel, user or topic field in channel_topic event"
+            ),
+        };
+
         println!(
-            "Received message '{}' ts {} from {} in channel {}",
-            text, ts, user, channel
+            "Received topic '{}' from {} in channel {}",
+            topic, user, channel
+        );
+
+        if let Some(irc_chan) = get_irc_channel(team_id, channel).await {
+            let setter = get_username(user).unwrap_or_else(|| user.to_owned());
+            let topic = slack_to_irc(topic).await;
+            let mut irc_chan_guard = irc_chan.write().await;
+            set_channel_topic(&mut irc_chan_guard, &setter, topic).await;
+        }
+
+        Ok(Response::new("".into()))
+    }
+
+    async fn handle_reaction_event_callback(
+        team_id: &str,
+        event_object: &Map<String, Value>,
+        added: bool,
+    ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        let (user, reaction, event_ts) = match (
+            event_object.get("user"),
+            event_object.get("reaction"),
+            event_object.get("event_ts"),
+        ) {
+            (
+                Some(Value::String(user)),
+                Some(Value::String(reaction)),
+                Some(Value::String(event_ts)),
+            ) => (user, reaction, event_ts),
+            _ => return_error!(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid user, reaction or event_ts field in reaction event"
+            ),
+        };
+
+        // Files and file comments can have reactions too, but we only relay messages
+        let item = event_object.get("item");
+        let (channel, ts) = match (
+            item.and_then(|v| v.get("type")).and_then(|v| v.as_str()),
+            item.and_then(|v| v.get("channel")).and_then(|v| v.as_str()),
+            item.and_then(|v| v.get("ts")).and_then(|v| v.as_str()),
+        ) {
+            (Some("message"), Some(channel), Some(ts)) => (channel, ts),
+            _ => return Ok(Response::new("".into())),
+        };
+
+        println!(
+            "Received reaction :{}: from {} to message ts {} in channel {}",
+            reaction, user, ts, channel
         );
 
+        let emoji = ":".to_owned() + reaction + ":";
+        let message = match get_message(channel, ts).await {
+            Some(original) => format!("\"{}\"", Self::excerpt(&original.text)),
+            None => "a message".to_owned(),
+        };
+        let action = if added {
+            make_ctcp_action(&format!("reacted {} to {}", emoji, message))
+        } else {
+            make_ctcp_action(&format!(
+                "removed their {} reaction from {}",
+                emoji, message
+            ))
+        };
+
+        if is_im_channel_id(channel) {
+            let lines = make_tagged_lines(channel, event_ts, Some(ts), &action);
+            Self::relay_to_irc(team_id, channel, user, "PRIVMSG", lines, false).await;
+            return Ok(Response::new("".into()));
+        }
+
+        let irc_chan = match get_irc_channel(team_id, channel).await {
+            Some(irc_chan) => irc_chan,
+            None => return Ok(Response::new("".into())),
+        };
         let username = get_username(user).unwrap_or_else(|| user.to_owned());
+        let react_tag = if added {
+            "+draft/react="
+        } else {
+            "+draft/unreact="
+        };
+        let tags = make_message_tags(channel, event_ts, Some(ts));
+        let mut react_tags = tags.clone();
+        react_tags.push(react_tag.to_owned() + &emoji);
 
-        if let Some(channel) = get_irc_channel(&channel).await {
-            let text = text.to_owned();
-            tokio::spawn(async move {
-                let channel_guard = channel.write().await;
-                let _ = channel_guard
-                    .send(
-                        Message {
-                            tags: Vec::new(),
-                            source: Some(username.clone() + "!~" + &username + "@slack.com"),
-                            command: "PRIVMSG".to_owned(),
-                            params: vec![channel_guard.name.to_owned(), text],
-                        },
-                        None,
+        // Clients with message-tags can show the reaction on the message itself
+        let irc_chan_guard = irc_chan.read().await;
+        let tagmsg = Message {
+            tags: react_tags,
+            source: Some(irc_source(&username)),
+            command: "TAGMSG".to_owned(),
+            params: vec![irc_chan_guard.name.clone()],
+        };
+        let _ = irc_chan_guard
+            .send_with_cap(tagmsg, "message-tags", None)
+            .await;
+        let privmsg = Message {
+            tags,
+            source: Some(irc_source(&username)),
+            command: "PRIVMSG".to_owned(),
+            params: vec![irc_chan_guard.name.clone(), action],
+        };
+        let _ = irc_chan_guard
+            .send_without_cap(privmsg, "message-tags", None)
+            .await;
+
+        Ok(Response::new("".into()))
+    }
+
+    async fn handle_user_change_event_callback(
+        team_id: &str,
+        event_object: &Map<String, Value>,
+    ) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+        let user_info: UserInfo = match event_object.get("user") {
+            Some(v) if v.is_object() => serde_json::from_value(v.clone())?,
+            _ => return_error!(
+                StatusCode::BAD_REQUEST,
+                "Missing or invalid user field in user_change event"
+            ),
+        };
+
+        let user_id = user_info.id.clone();
+        let old_away_message = get_away_message(&user_id);
+        register_user(user_info);
+        if get_away_message(&user_id) != old_away_message {
+            Self::update_away_status(team_id, &user_id).await;
+        }
+
+        Ok(Response::new("".into()))
+    }
+
+    // Sends the user's current away status to every bridged channel of the team
+    async fn update_away_status(team_id: &str, slack_user_id: &str) {
+        let username = match get_username(slack_user_id) {
+            Some(username) => username,
+            None => return,
+        };
+        let away_message = get_away_message(slack_user_id);
+
+        for irc_chan in get_irc_channels(team_id).await {
+            let mut irc_chan_guard = irc_chan.write().await;
+            set_virtual_user_away(&mut irc_chan_guard, &username, away_message.clone()).await;
+        }
+    }
+
+    // Shortens a message so it can be quoted when referring to it, without any formatting
+    fn excerpt(text: &str) -> String {
+        const MAX_EXCERPT_CHARS: usize = 24;
+        let text = text
+            .chars()
+            .map(|c| if c == '\n' { ' ' } else { c })
+            .filter(|c| !c.is_control())
+            .collect::<String>();
+        if text.chars().count() <= MAX_EXCERPT_CHARS {
+            return text;
+        }
+        text.chars().take(MAX_EXCERPT_CHARS).collect::<String>() + "..."
+    }
+
+    // Messages from IRC are only relayed to the other side of IMs, who hasn't seen them yet
+    async fn relay_to_irc(
+        team_id: &str,
+        slack_channel_id: &str,
+        user: &str,
+        command: &str,
+        lines: Vec<TaggedLine>,
+        from_irc: bool,
+    ) {
+        if is_im_channel_id(slack_channel_id) {
+            Self::relay_to_irc_im(team_id, slack_channel_id, user, command, lines, from_irc).await;
+        } else if !from_irc {
+            Self::relay_to_irc_channel(team_id, slack_channel_id, user, command, lines).await;
+        }
+    }
+
+    async fn relay_to_irc_im(
+        team_id: &str,
+        slack_channel_id: &str,
+        user: &str,
+        command: &str,
+        lines: Vec<TaggedLine>,
+        from_irc: bool,
+    ) {
+        let mut route = get_im_route(slack_channel_id).await;
+        if route.is_none() {
+            // Probably a brand new IM
+            refresh_ims(team_id).await;
+            route = get_im_route(slack_channel_id).await;
+        }
+        let client_routes = match route {
+            Some(route) => route,
+            None => {
+                println!("Received message in unknown IM {}", slack_channel_id);
+                return;
+            }
+        };
+
+        let targets = {
+            let clients = GLOBAL_CLIENTS.read().unwrap();
+            client_routes
+                .iter()
+                .filter_map(|(addr, other_user_id)| {
+                    clients.get(addr).map(|client| (client, other_user_id))
+                })
+                .filter(|(client, _)| !from_irc || client.user_id != user)
+                .map(|(client, other_user_id)| {
+                    let is_own = client.user_id == user;
+                    let other_username =
+                        get_username(other_user_id).unwrap_or_else(|| other_user_id.to_owned());
+                    (
+                        client.nick.clone(),
+                        client.irc_sender.clone(),
+                        other_username,
+                        is_own,
                     )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (nick, irc_sender, other_username, is_own) in targets {
+            // Our own messages sent from Slack show up as queries to the other side
+            let (source_nick, target_nick) = if is_own {
+                (nick, other_username)
+            } else {
+                (other_username, nick)
+            };
+            for (tags, line) in &lines {
+                let _ = irc_sender
+                    .send(Message {
+                        tags: tags.clone(),
+                        source: Some(irc_source(&source_nick)),
+                        command: command.to_owned(),
+                        params: vec![target_nick.clone(), line.clone()],
+                    })
                     .await;
-            });
+            }
         }
+    }
 
-        Ok(Response::new("".into()))
+    async fn relay_to_irc_channel(
+        team_id: &str,
+        slack_channel_id: &str,
+        user: &str,
+        command: &str,
+        lines: Vec<TaggedLine>,
+    ) {
+        let username = get_username(user).unwrap_or_else(|| user.to_owned());
+        let command = command.to_owned();
+
+        if let Some(channel) = get_irc_channel(team_id, slack_channel_id).await {
+            tokio::spawn(async move {
+                let channel_guard = channel.write().await;
+                for (tags, line) in lines {
+                    let _ = channel_guard
+                        .send(
+                            Message {
+                                tags,
+                                source: Some(irc_source(&username)),
+                                command: command.clone(),
+                                params: vec![channel_guard.name.to_owned(), line],
+                            },
+                            None,
+                        )
+                        .await;
+                }
+            });
+        }
     }
 }
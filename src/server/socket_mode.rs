@@ -0,0 +1,99 @@
+use super::{SlackAppServer, SlackAppServerState};
+use crate::slack::Slack;
+use futures::{SinkExt, StreamExt};
+use serde_json::{self, Value};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+// Delay before reconnecting after a Socket Mode connection fails
+const RECONNECT_DELAY_SECS: u64 = 5;
+
+impl SlackAppServer {
+    pub async fn start_socket_mode(app_token: String) {
+        if app_token.is_empty() {
+            panic!(
+                "Slack app-level token must not be empty for Socket Mode, check the server config"
+            );
+        }
+
+        // Socket Mode connections are authenticated by the app token, there are no signatures
+        let state: &'static SlackAppServerState = Box::leak(Box::new(SlackAppServerState {
+            signing_secret: String::new(),
+        }));
+        let slack = Arc::new(Slack::new(&app_token));
+
+        // Nobody waits on this task, so it must never stop, even if a connection panics
+        loop {
+            let connection_slack = slack.clone();
+            let connection = tokio::spawn(async move {
+                Self::run_socket_mode_connection(state, &connection_slack).await
+            });
+            match connection.await {
+                Ok(Ok(())) => println!("Slack Socket Mode connection closed, reconnecting"),
+                Ok(Err(err)) => {
+                    println!("Error in Slack Socket Mode connection: {}", err);
+                    tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY_SECS)).await;
+                }
+                Err(err) => {
+                    println!("Slack Socket Mode connection crashed: {}", err);
+                    tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY_SECS)).await;
+                }
+            }
+        }
+    }
+
+    async fn run_socket_mode_connection(
+        state: &'static SlackAppServerState,
+        slack: &Slack,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let url = slack.connections_open().await?;
+        let (ws_stream, _) = connect_async(url.as_str()).await?;
+        let (mut ws_write, mut ws_read) = ws_stream.split();
+
+        while let Some(ws_msg) = ws_read.next().await {
+            let text = match ws_msg? {
+                WsMessage::Text(text) => text,
+                WsMessage::Close(_) => break,
+                _ => continue,
+            };
+            let envelope: Value = serde_json::from_str(&text)?;
+
+            // Slack retries envelopes that aren't ack'd quickly, so do that before any processing
+            if let Some(envelope_id) = envelope.get("envelope_id").and_then(|v| v.as_str()) {
+                let ack = serde_json::json!({ "envelope_id": envelope_id });
+                ws_write.send(WsMessage::Text(ack.to_string())).await?;
+            }
+
+            match envelope.get("type").and_then(|v| v.as_str()) {
+                Some("hello") => println!("Connected to Slack in Socket Mode"),
+                Some("disconnect") => {
+                    println!("Slack asked us to reconnect: {}", envelope["reason"]);
+                    break;
+                }
+                Some("events_api") => {
+                    // A handler panicking must not take the whole connection down with it,
+                    // but events are still handled one at a time to keep them in order
+                    let payload = envelope["payload"].clone();
+                    let handler = tokio::spawn(async move {
+                        match Self::handle_event_callback(state, &payload).await {
+                            Ok(reply) if !reply.status().is_success() => {
+                                println!("Error processing Socket Mode event: {}", payload)
+                            }
+                            Err(err) => println!("Error processing Socket Mode event: {}", err),
+                            _ => (),
+                        }
+                    });
+                    if let Err(err) = handler.await {
+                        println!("Socket Mode event handler crashed: {}", err);
+                    }
+                }
+                _ => println!("Received unhandled Socket Mode envelope: {}", envelope),
+            }
+        }
+
+        Ok(())
+    }
+}
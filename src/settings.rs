@@ -6,6 +6,9 @@ use std::io::{Read, Write};
 use std::sync::{Arc, RwLock};
 
 static CONFIG_FILE_RELPATH: &str = ".config/rirc_slack.json";
+const DEFAULT_HISTORY_BACKFILL_COUNT: usize = 25;
+// conversations.history returns at most 999 messages per call
+const MAX_HISTORY_BACKFILL_COUNT: usize = 999;
 
 lazy_static! {
     pub static ref GLOBAL_SETTINGS: Arc<RwLock<Settings>> =
@@ -18,18 +21,44 @@ pub struct UserProfile {
     pub slack_token: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SlackAppTransport {
+    #[default]
+    Http, // Slack sends events to our public HTTP listener
+    SocketMode, // We open a WebSocket to Slack and receive events through it
+}
+
 #[derive(Serialize, Deserialize)]
 struct SettingsFile {
     pub irc_listen_addr: String,
     pub slack_app_listen_addr: String,
-    pub slack_app_verif_token: String,
+    #[serde(default)]
+    pub slack_app_signing_secret: String, // Only needed for the Http transport
+    #[serde(default)]
+    pub slack_app_transport: SlackAppTransport,
+    #[serde(default)]
+    pub slack_app_token: String,
+    #[serde(default)]
+    pub slack_app_public_url: String,
+    #[serde(default = "default_history_backfill_count")]
+    pub history_backfill_count: usize,
     pub user_profiles: Vec<UserProfile>,
 }
 
+fn default_history_backfill_count() -> usize {
+    DEFAULT_HISTORY_BACKFILL_COUNT
+}
+
 pub struct Settings {
     pub irc_listen_addr: String,
     pub slack_app_listen_addr: String,
-    pub slack_app_verif_token: String,
+    pub slack_app_signing_secret: String,
+    pub slack_app_transport: SlackAppTransport,
+    pub slack_app_token: String, // App-level token, only needed for Socket Mode
+    pub slack_app_public_url: String, // Where IRC users reach the Slack app listener, for files
+    // Messages replayed when joining a channel, 0 to disable, at most MAX_HISTORY_BACKFILL_COUNT
+    pub history_backfill_count: usize,
     pub user_profiles: HashMap<String, UserProfile>, // Names to profiles
 }
 
@@ -38,7 +67,11 @@ impl Default for Settings {
         Self {
             irc_listen_addr: "0.0.0.0:8080".to_owned(),
             slack_app_listen_addr: "0.0.0.0:8080".to_owned(),
-            slack_app_verif_token: String::new(),
+            slack_app_signing_secret: String::new(),
+            slack_app_transport: SlackAppTransport::default(),
+            slack_app_token: String::new(),
+            slack_app_public_url: String::new(),
+            history_backfill_count: DEFAULT_HISTORY_BACKFILL_COUNT,
             user_profiles: HashMap::new(),
         }
     }
@@ -65,7 +98,13 @@ fn read_settings() -> Result<Settings, Box<dyn Error>> {
     Ok(Settings {
         irc_listen_addr: settings_file.irc_listen_addr,
         slack_app_listen_addr: settings_file.slack_app_listen_addr,
-        slack_app_verif_token: settings_file.slack_app_verif_token,
+        slack_app_signing_secret: settings_file.slack_app_signing_secret,
+        slack_app_transport: settings_file.slack_app_transport,
+        slack_app_token: settings_file.slack_app_token,
+        slack_app_public_url: settings_file.slack_app_public_url,
+        history_backfill_count: settings_file
+            .history_backfill_count
+            .min(MAX_HISTORY_BACKFILL_COUNT),
         user_profiles,
     })
 }
@@ -85,7 +124,11 @@ pub fn save_settings() -> Result<(), Box<dyn Error>> {
     let settings_file = SettingsFile {
         irc_listen_addr: settings.irc_listen_addr.clone(),
         slack_app_listen_addr: settings.slack_app_listen_addr.clone(),
-        slack_app_verif_token: settings.slack_app_verif_token.clone(),
+        slack_app_signing_secret: settings.slack_app_signing_secret.clone(),
+        slack_app_transport: settings.slack_app_transport,
+        slack_app_token: settings.slack_app_token.clone(),
+        slack_app_public_url: settings.slack_app_public_url.clone(),
+        history_backfill_count: settings.history_backfill_count,
         user_profiles,
     };
     let encoded = serde_json::to_string(&settings_file)?;
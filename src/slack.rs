@@ -1,13 +1,19 @@
 use http::SlackHttpClient;
-use serde_json::{self, Value};
+use serde_json::{self, json, Value};
 use std::error::Error;
 use std::vec::Vec;
 
+mod auth;
 mod channel;
+mod file;
 mod http;
+mod message;
 mod user;
 
+pub use self::auth::AuthInfo;
 pub use self::channel::Channel;
+pub use self::file::File;
+pub use self::message::Message;
 pub use self::user::UserInfo;
 
 pub struct Slack {
@@ -31,23 +37,37 @@ impl Slack {
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub async fn test_auth(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pub async fn test_auth(&self) -> Result<AuthInfo, Box<dyn Error + Send + Sync>> {
         let params = [("token", &self.token)];
-        self.http_client.api_call("auth.test", &params).await?;
-        Ok(())
+        let json = self.http_client.api_call("auth.test", &params).await?;
+        Ok(serde_json::from_value(json)?)
+    }
+
+    // Must be called with an app-level token, returns the Socket Mode WebSocket URL
+    pub async fn connections_open(&self) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let mut json = self
+            .http_client
+            .api_call::<[(&str, &str)]>("apps.connections.open", &[])
+            .await?;
+        Ok(serde_json::from_value(json["url"].take())?)
     }
 
     pub async fn post_message(
         &self,
         channel: &str,
         message: &str,
+        thread_ts: Option<&str>,
+        reply_broadcast: bool,
     ) -> Result<String, Box<dyn Error + Send + Sync>> {
-        let params = [
+        let mut params = vec![
             ("channel", Value::from(channel)),
             ("text", Value::from(message)),
             ("as_user", Value::from(true)),
         ];
+        if let Some(thread_ts) = thread_ts {
+            params.push(("thread_ts", Value::from(thread_ts)));
+            params.push(("reply_broadcast", Value::from(reply_broadcast)));
+        }
         let mut json = self
             .http_client
             .api_call("chat.postMessage", &params)
@@ -55,28 +75,120 @@ impl Slack {
         Ok(serde_json::from_value(json["ts"].take()).unwrap())
     }
 
-    // NOTE: Won't return archived channels
-    pub async fn channels_list(&self) -> Result<Vec<Channel>, Box<dyn Error + Send + Sync>> {
+    // Posts a /me, returns its ts like post_message
+    pub async fn me_message(
+        &self,
+        channel: &str,
+        message: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let params = [("channel", channel), ("text", message)];
+        let mut json = self.http_client.api_call("chat.meMessage", &params).await?;
+        Ok(serde_json::from_value(json["ts"].take())?)
+    }
+
+    pub async fn delete_message(
+        &self,
+        channel: &str,
+        ts: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = [
+            ("channel", Value::from(channel)),
+            ("ts", Value::from(ts)),
+            ("as_user", Value::from(true)),
+        ];
+        self.http_client.api_call("chat.delete", &params).await?;
+        Ok(())
+    }
+
+    // Starts downloading a file from its url_private_download, the body is left to stream
+    pub async fn download_file(
+        &self,
+        url: &str,
+    ) -> Result<reqwest::Response, Box<dyn Error + Send + Sync>> {
+        self.http_client.download(url).await
+    }
+
+    // Uploads a file and shares it in the channel, the thread if thread_ts is set
+    pub async fn upload_file(
+        &self,
+        channel: &str,
+        thread_ts: Option<&str>,
+        filename: &str,
+        contents: Vec<u8>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = [
+            ("filename", Value::from(filename)),
+            ("length", Value::from(contents.len())),
+        ];
+        let json = self
+            .http_client
+            .api_call("files.getUploadURLExternal", &params)
+            .await?;
+        let (upload_url, file_id) = match (json["upload_url"].as_str(), json["file_id"].as_str()) {
+            (Some(upload_url), Some(file_id)) => (upload_url, file_id),
+            _ => return Err(From::from("Slack didn't give us an upload URL")),
+        };
+
+        self.http_client.upload(upload_url, contents).await?;
+
+        let files = json!([{ "id": file_id, "title": filename }]);
+        let mut params = vec![
+            ("files", Value::from(files.to_string())),
+            ("channel_id", Value::from(channel)),
+        ];
+        if let Some(thread_ts) = thread_ts {
+            params.push(("thread_ts", Value::from(thread_ts)));
+        }
+        self.http_client
+            .api_call("files.completeUploadExternal", &params)
+            .await?;
+        Ok(())
+    }
+
+    // The reaction is the name of the emoji, without colons
+    pub async fn reactions_add(
+        &self,
+        channel: &str,
+        ts: &str,
+        reaction: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = [
+            ("channel", Value::from(channel)),
+            ("timestamp", Value::from(ts)),
+            ("name", Value::from(reaction)),
+        ];
+        self.http_client.api_call("reactions.add", &params).await?;
+        Ok(())
+    }
+
+    // NOTE: Won't return archived conversations. Types is a comma-separated list, see the API docs
+    pub async fn conversations_list(
+        &self,
+        types: &str,
+    ) -> Result<Vec<Channel>, Box<dyn Error + Send + Sync>> {
         let mut result = Vec::new();
         let mut next_cursor = None;
 
         loop {
             let params = if let Some(cursor) = next_cursor {
                 [
+                    ("types", Value::from(types)),
                     ("exclude_archived", Value::from(true)),
-                    ("exclude_members", Value::from(true)),
                     ("limit", Value::from(500)),
                     ("cursor", Value::from(cursor)),
                 ]
             } else {
                 [
+                    ("types", Value::from(types)),
                     ("exclude_archived", Value::from(true)),
-                    ("exclude_members", Value::from(true)),
                     ("limit", Value::from(500)),
                     ("cursor", Value::from("")),
                 ]
             };
-            let mut json = self.http_client.api_call("channels.list", &params).await?;
+            let mut json = self
+                .http_client
+                .api_call("conversations.list", &params)
+                .await?;
             let jchannels = json["channels"].take();
             let mut next_channels = serde_json::from_value(jchannels)?;
             result.append(&mut next_channels);
@@ -95,6 +207,227 @@ impl Slack {
         Ok(result)
     }
 
+    // Opens (or finds) the IM with a user, returns its channel ID
+    pub async fn conversations_open(
+        &self,
+        user: &str,
+    ) -> Result<String, Box<dyn Error + Send + Sync>> {
+        let params = [("users", Value::from(user))];
+        let mut json = self
+            .http_client
+            .api_call("conversations.open", &params)
+            .await?;
+        Ok(serde_json::from_value(json["channel"]["id"].take())?)
+    }
+
+    pub async fn conversations_info(
+        &self,
+        channel: &str,
+    ) -> Result<Channel, Box<dyn Error + Send + Sync>> {
+        let params = [("channel", Value::from(channel))];
+        let mut json = self
+            .http_client
+            .api_call("conversations.info", &params)
+            .await?;
+        Ok(serde_json::from_value(json["channel"].take())?)
+    }
+
+    pub async fn conversations_join(
+        &self,
+        channel: &str,
+    ) -> Result<Channel, Box<dyn Error + Send + Sync>> {
+        let params = [("channel", Value::from(channel))];
+        let mut json = self
+            .http_client
+            .api_call("conversations.join", &params)
+            .await?;
+        Ok(serde_json::from_value(json["channel"].take())?)
+    }
+
+    pub async fn conversations_create(
+        &self,
+        name: &str,
+    ) -> Result<Channel, Box<dyn Error + Send + Sync>> {
+        let params = [("name", Value::from(name))];
+        let mut json = self
+            .http_client
+            .api_call("conversations.create", &params)
+            .await?;
+        Ok(serde_json::from_value(json["channel"].take())?)
+    }
+
+    pub async fn conversations_leave(
+        &self,
+        channel: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = [("channel", Value::from(channel))];
+        self.http_client
+            .api_call("conversations.leave", &params)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn conversations_set_topic(
+        &self,
+        channel: &str,
+        topic: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = [
+            ("channel", Value::from(channel)),
+            ("topic", Value::from(topic)),
+        ];
+        self.http_client
+            .api_call("conversations.setTopic", &params)
+            .await?;
+        Ok(())
+    }
+
+    // Returns the messages of the conversation between oldest and latest (if set), newest first
+    pub async fn conversations_history(
+        &self,
+        channel: &str,
+        oldest: Option<&str>,
+        latest: Option<&str>,
+        inclusive: bool,
+        limit: usize,
+    ) -> Result<Vec<Message>, Box<dyn Error + Send + Sync>> {
+        let mut params = vec![
+            ("channel", Value::from(channel)),
+            ("inclusive", Value::from(inclusive)),
+            ("limit", Value::from(limit)),
+        ];
+        if let Some(oldest) = oldest {
+            params.push(("oldest", Value::from(oldest)));
+        }
+        if let Some(latest) = latest {
+            params.push(("latest", Value::from(latest)));
+        }
+        let mut json = self
+            .http_client
+            .api_call("conversations.history", &params)
+            .await?;
+        Ok(serde_json::from_value(json["messages"].take())?)
+    }
+
+    // Returns the whole thread, the parent message first
+    pub async fn conversations_replies(
+        &self,
+        channel: &str,
+        thread_ts: &str,
+    ) -> Result<Vec<Message>, Box<dyn Error + Send + Sync>> {
+        let mut result = Vec::new();
+        let mut next_cursor = None;
+
+        loop {
+            let params = if let Some(cursor) = next_cursor {
+                [
+                    ("channel", Value::from(channel)),
+                    ("ts", Value::from(thread_ts)),
+                    ("limit", Value::from(200)),
+                    ("cursor", Value::from(cursor)),
+                ]
+            } else {
+                [
+                    ("channel", Value::from(channel)),
+                    ("ts", Value::from(thread_ts)),
+                    ("limit", Value::from(200)),
+                    ("cursor", Value::from("")),
+                ]
+            };
+            let mut json = self
+                .http_client
+                .api_call("conversations.replies", &params)
+                .await?;
+            let jmessages = json["messages"].take();
+            let mut next_messages = serde_json::from_value(jmessages)?;
+            result.append(&mut next_messages);
+
+            if let Some(meta) = json.get("response_metadata") {
+                if let Some(Some(cursor)) = meta.get("next_cursor").map(|v| v.as_str()) {
+                    if !cursor.is_empty() {
+                        next_cursor = Some(cursor.to_owned());
+                        continue;
+                    }
+                }
+            }
+            break;
+        }
+
+        Ok(result)
+    }
+
+    // Returns the IDs of the members of the conversation
+    pub async fn conversations_members(
+        &self,
+        channel: &str,
+    ) -> Result<Vec<String>, Box<dyn Error + Send + Sync>> {
+        let mut result = Vec::new();
+        let mut next_cursor = None;
+
+        loop {
+            let params = if let Some(cursor) = next_cursor {
+                [
+                    ("channel", Value::from(channel)),
+                    ("limit", Value::from(500)),
+                    ("cursor", Value::from(cursor)),
+                ]
+            } else {
+                [
+                    ("channel", Value::from(channel)),
+                    ("limit", Value::from(500)),
+                    ("cursor", Value::from("")),
+                ]
+            };
+            let mut json = self
+                .http_client
+                .api_call("conversations.members", &params)
+                .await?;
+            let jmembers = json["members"].take();
+            let mut next_members = serde_json::from_value(jmembers)?;
+            result.append(&mut next_members);
+
+            if let Some(meta) = json.get("response_metadata") {
+                if let Some(Some(cursor)) = meta.get("next_cursor").map(|v| v.as_str()) {
+                    if !cursor.is_empty() {
+                        next_cursor = Some(cursor.to_owned());
+                        continue;
+                    }
+                }
+            }
+            break;
+        }
+
+        Ok(result)
+    }
+
+    // Presence is either "auto" or "away"
+    pub async fn users_set_presence(
+        &self,
+        presence: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = [("presence", presence)];
+        self.http_client
+            .api_call("users.setPresence", &params)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn users_profile_set_status(
+        &self,
+        status_text: &str,
+        status_emoji: &str,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let profile = json!({
+            "status_text": status_text,
+            "status_emoji": status_emoji,
+        });
+        let params = [("profile", profile.to_string())];
+        self.http_client
+            .api_call("users.profile.set", &params)
+            .await?;
+        Ok(())
+    }
+
     pub async fn users_list(&self) -> Result<Vec<UserInfo>, Box<dyn Error + Send + Sync>> {
         let mut result = Vec::new();
         let mut next_cursor = None;
@@ -0,0 +1,5 @@
+#[derive(Deserialize)]
+pub struct AuthInfo {
+    pub team_id: String,
+    pub user_id: String,
+}
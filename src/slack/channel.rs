@@ -1,10 +1,39 @@
 #[derive(Deserialize)]
 pub struct Channel {
     pub id: String,
-    pub name: String,
-    pub creator: String,
+    pub name: Option<String>, // IMs have no name
+    pub user: Option<String>, // Only for IMs, the user on the other side
     pub created: u64,
+    pub num_members: Option<u64>, // Not set for IMs and mpims
+    pub topic: Option<ChannelTopic>,
+    pub purpose: Option<ChannelTopic>,
+    #[serde(default)]
     pub is_member: bool,
+    #[serde(default)]
     pub is_channel: bool,
+    #[serde(default)]
+    pub is_private: bool,
+    #[serde(default)]
+    pub is_im: bool,
+    #[serde(default)]
+    pub is_mpim: bool,
+    #[serde(default)]
     pub is_archived: bool,
 }
+
+// Used for both topics and purposes
+#[derive(Deserialize)]
+pub struct ChannelTopic {
+    pub value: String,
+    pub creator: String,
+}
+
+impl Channel {
+    // Slack shows the purpose when there's no topic, so do the same
+    pub fn topic_or_purpose(&self) -> Option<&ChannelTopic> {
+        self.topic
+            .iter()
+            .chain(self.purpose.iter())
+            .find(|topic| !topic.value.is_empty())
+    }
+}
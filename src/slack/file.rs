@@ -0,0 +1,11 @@
+#[derive(Deserialize, Clone)]
+pub struct File {
+    pub id: String,
+    pub name: Option<String>, // Not set for files hidden by the workspace's retention policy
+    pub title: Option<String>,
+    pub mimetype: Option<String>,
+    pub pretty_type: Option<String>,
+    pub size: Option<u64>,
+    pub url_private_download: Option<String>, // Needs a token to download
+    pub permalink: Option<String>,
+}
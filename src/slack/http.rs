@@ -2,7 +2,7 @@ static API_BASE: &str = "https://slack.com/api/";
 
 use hyper::header::HeaderValue;
 use reqwest::header::HeaderMap;
-use reqwest::{header, Client, ClientBuilder};
+use reqwest::{header, Client, ClientBuilder, Response};
 use serde::ser::Serialize;
 use serde_json::Value;
 use std::error::Error;
@@ -54,4 +54,30 @@ impl SlackHttpClient {
         }
         Ok(json)
     }
+
+    // Private Slack URLs, like those of files, need the same authorization as the API
+    pub async fn download(&self, url: &str) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        let res = self.client.get(url).send().await?;
+        if !res.status().is_success() {
+            return Err(From::from(
+                "Download failed with status ".to_owned() + &res.status().to_string(),
+            ));
+        }
+        Ok(res)
+    }
+
+    // Sends raw contents to one of the upload URLs Slack hands out
+    pub async fn upload(
+        &self,
+        url: &str,
+        contents: Vec<u8>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let res = self.client.post(url).body(contents).send().await?;
+        if !res.status().is_success() {
+            return Err(From::from(
+                "Upload failed with status ".to_owned() + &res.status().to_string(),
+            ));
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,28 @@
+use super::File;
+use serde_json::Value;
+
+#[derive(Deserialize, Clone)]
+pub struct Message {
+    pub ts: String,
+    pub user: Option<String>, // Not set for some bot messages
+    #[serde(default)]
+    pub text: String, // Can be missing for messages made of blocks or attachments
+    pub subtype: Option<String>,
+    pub thread_ts: Option<String>,
+    #[serde(default)]
+    pub reply_count: u32, // Only set on thread parents
+    #[serde(default)]
+    pub files: Vec<File>,
+    pub username: Option<String>, // Set by bots posting under a custom name
+    pub bot_id: Option<String>,
+    pub bot_profile: Option<BotProfile>,
+    #[serde(default)]
+    pub blocks: Vec<Value>,
+    #[serde(default)]
+    pub attachments: Vec<Value>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BotProfile {
+    pub name: Option<String>,
+}
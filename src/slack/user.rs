@@ -1,10 +1,30 @@
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone)]
 pub struct UserInfo {
     pub id: String,
     pub team_id: String,
     pub name: String,
     pub real_name: Option<String>,
+    pub tz: Option<String>,
+    pub tz_label: Option<String>,
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(default)]
+    pub is_owner: bool,
     pub is_bot: bool,
     pub is_app_user: bool,
     pub deleted: bool,
+    #[serde(default)]
+    pub profile: UserProfile,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct UserProfile {
+    #[serde(default)]
+    pub title: String,
+    pub email: Option<String>, // Needs the users:read.email scope
+    pub image_192: Option<String>,
+    #[serde(default)]
+    pub status_text: String,
+    #[serde(default)]
+    pub status_emoji: String,
 }
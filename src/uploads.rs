@@ -0,0 +1,381 @@
+use crate::client::{Client, IRC_SERVER_NAME};
+use crate::files::format_size;
+use crate::slack::Slack;
+use reqwest::redirect::Policy;
+use reqwest::{header, Client as HttpClient, Url};
+use rirc_server::{ClientSender as IRCClientSender, Message as IRCMsg};
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
+
+// Sending this followed by a URL in a conversation uploads the file at that URL to it
+pub const UPLOAD_COMMAND: &str = "!upload ";
+
+// Files bigger than this are refused, since we hold them in memory until they're uploaded
+const MAX_UPLOAD_SIZE: u64 = 100 * 1024 * 1024;
+const DCC_CONNECT_TIMEOUT_SECS: u64 = 30;
+// A DCC sender that stops sending for this long is given up on
+const DCC_READ_TIMEOUT_SECS: u64 = 60;
+const MAX_URL_REDIRECTS: usize = 5;
+
+pub struct DccOffer {
+    pub filename: String,
+    pub port: u16, // 0 for passive DCC
+    pub size: Option<u64>,
+}
+
+// The Slack conversation an upload goes to, and the IRC client we tell how it went
+pub struct UploadTarget {
+    slack: Arc<Slack>,
+    channel_id: String,
+    thread_ts: Option<String>,
+    irc_sender: IRCClientSender,
+    nick: String,
+}
+
+impl UploadTarget {
+    pub fn new(client: &Client, channel_id: &str, thread_ts: Option<String>) -> UploadTarget {
+        UploadTarget {
+            slack: client.slack.clone(),
+            channel_id: channel_id.to_owned(),
+            thread_ts,
+            irc_sender: client.irc_sender.clone(),
+            nick: client.nick.clone(),
+        }
+    }
+
+    async fn notice(&self, text: &str) {
+        let _ = self
+            .irc_sender
+            .send(IRCMsg {
+                tags: Vec::new(),
+                source: Some(IRC_SERVER_NAME.to_owned()),
+                command: "NOTICE".to_owned(),
+                params: vec![self.nick.clone(), text.to_owned()],
+            })
+            .await;
+    }
+}
+
+// Parses a CTCP "DCC SEND <filename> <ip> <port> [size]" offer
+pub fn parse_dcc_send(text: &str) -> Option<DccOffer> {
+    let args = text.strip_prefix("\x01DCC SEND ")?.trim_end_matches('\x01');
+    let (filename, rest) = match args.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        }
+        None => args.split_once(' ')?,
+    };
+
+    // The IP is skipped, we only ever connect back to the client's own address
+    let mut rest = rest.split_whitespace().skip(1);
+    let port = rest.next()?.parse().ok()?;
+    let size = rest.next().and_then(|size| size.parse().ok());
+
+    // Don't let the sender pick a path, only a name
+    let filename = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .filter(|filename| !filename.is_empty())
+        .unwrap_or("upload");
+    Some(DccOffer {
+        filename: filename.to_owned(),
+        port,
+        size,
+    })
+}
+
+pub async fn upload_from_dcc(target: UploadTarget, client_ip: IpAddr, offer: DccOffer) {
+    if offer.port == 0 {
+        target
+            .notice("Passive DCC isn't supported, please disable it to send files to Slack")
+            .await;
+        return;
+    }
+    if offer.size.is_some_and(|size| size > MAX_UPLOAD_SIZE) {
+        target
+            .notice(&format!(
+                "{} is too big to upload to Slack through the gateway",
+                offer.filename
+            ))
+            .await;
+        return;
+    }
+
+    target
+        .notice(&format!("Receiving {} over DCC...", offer.filename))
+        .await;
+    let contents = match receive_dcc(SocketAddr::new(client_ip, offer.port), offer.size).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            target
+                .notice(&format!(
+                    "DCC transfer of {} failed: {}",
+                    offer.filename, err
+                ))
+                .await;
+            return;
+        }
+    };
+
+    upload(&target, &offer.filename, contents).await;
+}
+
+async fn receive_dcc(
+    addr: SocketAddr,
+    size: Option<u64>,
+) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let connect = TcpStream::connect(addr);
+    let mut stream = timeout(Duration::from_secs(DCC_CONNECT_TIMEOUT_SECS), connect).await??;
+
+    let mut contents = Vec::new();
+    let mut buf = [0u8; 16 * 1024];
+    while size.map_or(true, |size| (contents.len() as u64) < size) {
+        let read = stream.read(&mut buf);
+        let len = timeout(Duration::from_secs(DCC_READ_TIMEOUT_SECS), read).await??;
+        if len == 0 {
+            break;
+        }
+        contents.extend_from_slice(&buf[..len]);
+        if contents.len() as u64 > MAX_UPLOAD_SIZE {
+            return Err(From::from("File is too big"));
+        }
+
+        // DCC acks the number of bytes received so far, as 32 bits
+        let acked = (contents.len() as u64 & 0xffff_ffff) as u32;
+        stream.write_all(&acked.to_be_bytes()).await?;
+    }
+
+    if size.is_some_and(|size| (contents.len() as u64) < size) {
+        return Err(From::from("Transfer ended before the end of the file"));
+    }
+    Ok(contents)
+}
+
+pub async fn upload_from_url(target: UploadTarget, url: String) {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        target
+            .notice("Can only upload files from http(s) URLs")
+            .await;
+        return;
+    }
+    let filename = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|filename| !filename.is_empty())
+        .unwrap_or("upload")
+        .to_owned();
+
+    target.notice(&format!("Downloading {}...", url)).await;
+    let contents = match download(&url).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            target
+                .notice(&format!("Download of {} failed: {}", url, err))
+                .await;
+            return;
+        }
+    };
+
+    upload(&target, &filename, contents).await;
+}
+
+// Downloads the file at a URL, as long as it's on the public internet. Otherwise anyone on IRC
+// could make the gateway fetch things only it can reach, like cloud metadata or local services.
+async fn download(url: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let mut url = Url::parse(url)?;
+    for _ in 0..=MAX_URL_REDIRECTS {
+        // Redirects are followed by hand, so their targets get checked too
+        let res = public_client(&url).await?.get(url.clone()).send().await?;
+        if res.status().is_redirection() {
+            let location = res
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .ok_or("Redirected without a location")?;
+            url = url.join(location)?;
+            continue;
+        }
+        if !res.status().is_success() {
+            return Err(From::from(
+                "Request failed with status ".to_owned() + &res.status().to_string(),
+            ));
+        }
+        if res
+            .content_length()
+            .is_some_and(|len| len > MAX_UPLOAD_SIZE)
+        {
+            return Err(From::from("File is too big"));
+        }
+        let contents = res.bytes().await?;
+        if contents.len() as u64 > MAX_UPLOAD_SIZE {
+            return Err(From::from("File is too big"));
+        }
+        return Ok(contents.to_vec());
+    }
+    Err(From::from("Too many redirects"))
+}
+
+// A client that can only reach the URL's host at a public address. The host is resolved once
+// and pinned, so it can't switch to a private address between the check and the request.
+async fn public_client(url: &Url) -> Result<HttpClient, Box<dyn Error + Send + Sync>> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(From::from("Can only download from http(s) URLs"));
+    }
+    let port = url.port_or_known_default().ok_or("URL has no port")?;
+    let host = url.host_str().ok_or("URL has no host")?;
+    // IPv6 hosts are bracketed in URLs
+    let (domain, addrs) = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => (None, vec![SocketAddr::new(ip, port)]),
+        Err(_) => (Some(host), lookup_host((host, port)).await?.collect()),
+    };
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())) {
+        return Err(From::from("Can only download from public addresses"));
+    }
+
+    let mut builder = HttpClient::builder().redirect(Policy::none());
+    if let Some(domain) = domain {
+        builder = builder.resolve(domain, addrs[0]);
+    }
+    Ok(builder.build()?)
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b)) // Carrier-grade NAT
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (18..20).contains(&b))) // Benchmarking
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ipv4) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ipv4));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // Unique local
+                || (first & 0xffc0) == 0xfe80 // Link-local
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8)) // Documentation
+        }
+    }
+}
+
+async fn upload(target: &UploadTarget, filename: &str, contents: Vec<u8>) {
+    target
+        .notice(&format!(
+            "Uploading {} ({}) to Slack...",
+            filename,
+            format_size(contents.len() as u64)
+        ))
+        .await;
+    let result = target
+        .slack
+        .upload_file(
+            &target.channel_id,
+            target.thread_ts.as_deref(),
+            filename,
+            contents,
+        )
+        .await;
+    match result {
+        Ok(()) => target.notice(&format!("Uploaded {}", filename)).await,
+        Err(err) => {
+            target
+                .notice(&format!("Upload of {} failed: {}", filename, err))
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_ips_are_public() {
+        let private = [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+        ];
+        for ip in &private {
+            assert!(!is_public_ip(ip.parse().unwrap()), "{} is not public", ip);
+        }
+
+        let public = ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"];
+        for ip in &public {
+            assert!(is_public_ip(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[test]
+    fn dcc_send_offers() {
+        let cases = [
+            (
+                "\x01DCC SEND file.txt 3232235777 5000 1234\x01",
+                Some(("file.txt", 5000, Some(1234))),
+            ),
+            (
+                "\x01DCC SEND \"my file.txt\" 3232235777 5000 12\x01",
+                Some(("my file.txt", 5000, Some(12))),
+            ),
+            (
+                "\x01DCC SEND ../../etc/passwd 3232235777 5000 12\x01",
+                Some(("passwd", 5000, Some(12))),
+            ),
+            (
+                "\x01DCC SEND \"..\\..\\boot.ini\" 3232235777 5000 12\x01",
+                Some(("boot.ini", 5000, Some(12))),
+            ),
+            (
+                "\x01DCC SEND \"dir/\" 3232235777 5000 12\x01",
+                Some(("upload", 5000, Some(12))),
+            ),
+            (
+                "\x01DCC SEND file.txt 3232235777 5000\x01",
+                Some(("file.txt", 5000, None)),
+            ),
+            (
+                "\x01DCC SEND file.txt 3232235777 0 1234 42\x01",
+                Some(("file.txt", 0, Some(1234))),
+            ),
+            ("\x01DCC SEND file.txt 3232235777\x01", None),
+            ("\x01DCC SEND \"unterminated 3232235777 5000\x01", None),
+            ("\x01DCC CHAT chat 3232235777 5000\x01", None),
+        ];
+        for (text, expected) in &cases {
+            let offer = parse_dcc_send(text).map(|offer| (offer.filename, offer.port, offer.size));
+            let expected = expected.map(|(filename, port, size)| (filename.to_owned(), port, size));
+            assert_eq!(offer, expected, "{:?}", text);
+        }
+    }
+}
@@ -1,16 +1,66 @@
+use crate::slack::UserInfo;
+use rirc_server::VirtualUser;
 use std::collections::HashMap;
 use std::sync::RwLock;
 
 lazy_static! {
-    static ref GLOBAL_USERNAMES: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+    static ref GLOBAL_USERS: RwLock<HashMap<String, UserInfo>> = RwLock::new(HashMap::new());
 }
 
-pub fn register_username(slack_user_id: String, username: String) {
-    let mut users_guard = GLOBAL_USERNAMES.write().expect("Usernames write lock");
-    users_guard.insert(slack_user_id, username);
+pub fn register_user(user_info: UserInfo) {
+    let mut users_guard = GLOBAL_USERS.write().expect("Users write lock");
+    users_guard.insert(user_info.id.clone(), user_info);
 }
 
 pub fn get_username(slack_user_id: &str) -> Option<String> {
-    let users_guard = GLOBAL_USERNAMES.read().expect("Usernames read lock");
+    let users_guard = GLOBAL_USERS.read().expect("Users read lock");
+    users_guard.get(slack_user_id).map(|user| user.name.clone())
+}
+
+pub fn get_user_info(slack_user_id: &str) -> Option<UserInfo> {
+    let users_guard = GLOBAL_USERS.read().expect("Users read lock");
     users_guard.get(slack_user_id).cloned()
 }
+
+pub fn get_user_id(username: &str) -> Option<String> {
+    let users_guard = GLOBAL_USERS.read().expect("Users read lock");
+    users_guard
+        .values()
+        .find(|user| user.name.eq_ignore_ascii_case(username))
+        .map(|user| user.id.clone())
+}
+
+// Slack never sends presence changes to apps, so users with a status set are the ones shown as
+// away on IRC, with their status as the away message
+pub fn get_away_message(slack_user_id: &str) -> Option<String> {
+    let status = format_status(&get_user_info(slack_user_id)?);
+    if status.is_empty() {
+        None
+    } else {
+        Some(status)
+    }
+}
+
+// The Slack status of the user with its emoji, empty if they have none
+pub fn format_status(user_info: &UserInfo) -> String {
+    let profile = &user_info.profile;
+    let status = profile.status_emoji.clone() + " " + &profile.status_text;
+    status.trim().to_owned()
+}
+
+// The nick!user@host Slack users appear as on IRC
+pub fn irc_source(username: &str) -> String {
+    username.to_owned() + "!~" + username + "@slack.com"
+}
+
+pub fn make_virtual_user(user_info: &UserInfo) -> VirtualUser {
+    VirtualUser {
+        nick: user_info.name.clone(),
+        user: "~".to_owned() + &user_info.name,
+        host: "slack.com".to_owned(),
+        realname: user_info
+            .real_name
+            .clone()
+            .unwrap_or_else(|| user_info.name.clone()),
+    }
+}